@@ -1,11 +1,13 @@
-use serde_bytes;
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use thiserror::Error;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BencodeValue {
     List(Vec<BencodeValue>),
-    Map(HashMap<serde_bytes::ByteBuf, BencodeValue>),
+    // a BTreeMap keeps the keys sorted, which is what canonical bencode wants
+    Map(BTreeMap<serde_bytes::ByteBuf, BencodeValue>),
     String(serde_bytes::ByteBuf),
     Int(i64),
 }
@@ -29,101 +31,269 @@ impl BencodeValue {
             }
         }
     }
-}
 
-#[allow(dead_code)]
-pub fn decode_bencoded_value(encoded_value: Vec<u8>) -> (BencodeValue, usize) {
-    let c = encoded_value.iter().next().unwrap().to_owned() as char;
-    match (c, encoded_value.clone()) {
-        ('d', encoded) => {
-            let mut idx = 1;
-            let mut vals = HashMap::new();
-            while let Some(next_byte) = encoded[idx..].iter().next() {
-                let c = next_byte.to_owned() as char;
-                if c == 'e' {
-                    idx += 1;
-                } else {
-                    // let (BencodeValue::String(key_serde_val), key_len) =
-                    let (key_serde_val, key_len) = decode_bencoded_value(encoded[idx..].to_vec());
-                    let (val, val_len) = decode_bencoded_value(encoded[idx + key_len..].to_vec());
-                    match key_serde_val {
-                        BencodeValue::String(s) => {
-                            vals.insert(s, val);
-                        }
-                        _ => {
-                            panic!("dict keys must be byte strings")
-                        }
-                    }
-                    idx += key_len + val_len;
-                }
-            }
-            (BencodeValue::Map(vals), idx)
+    /// Looks up `key` if this is a dict.
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        match self {
+            BencodeValue::Map(m) => m.get(serde_bytes::Bytes::new(key.as_bytes())),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::String(s) => Some(s),
+            _ => None,
         }
-        ('l', encoded) => {
-            let mut idx = 1;
-            let mut vals = Vec::new();
-            while let Some(next_byte) = encoded[idx..].iter().next() {
-                let c = next_byte.to_owned() as char;
-                if c == 'e' {
-                    idx += 1;
-                    return (BencodeValue::List(vals), idx);
-                } else {
-                    // println!("calling recursive");
-                    let (val, i) = decode_bencoded_value(encoded[idx..].to_vec());
-                    vals.push(val);
-                    idx += i;
+    }
+
+    /// Encodes the value as canonical bencode: dict keys in sorted order and
+    /// integers in their shortest form.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            BencodeValue::Int(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            }
+            BencodeValue::String(s) => encode_bytes(s, out),
+            BencodeValue::List(l) => {
+                out.push(b'l');
+                for item in l {
+                    item.encode_into(out);
                 }
+                out.push(b'e');
             }
-            (BencodeValue::List(vals), idx)
-        }
-        ('i', encoded) => {
-            let mut step = 1;
-            let mut end_idx = 0;
-            let mut num_bytes = String::new();
-            while step < encoded.len() {
-                if encoded[step] == 'e' as u8 {
-                    end_idx = step;
-                    break;
-                } else {
-                    num_bytes.push(encoded[step] as char);
+            BencodeValue::Map(m) => {
+                out.push(b'd');
+                for (k, v) in m.iter() {
+                    encode_bytes(k, out);
+                    v.encode_into(out);
                 }
-                step += 1;
+                out.push(b'e');
             }
+        }
+    }
+
+    /// Builds a dict from string keys, for assembling messages by hand.
+    pub fn dict<K: AsRef<[u8]>>(entries: impl IntoIterator<Item = (K, BencodeValue)>) -> Self {
+        BencodeValue::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (serde_bytes::ByteBuf::from(k.as_ref().to_vec()), v))
+                .collect(),
+        )
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+impl From<i64> for BencodeValue {
+    fn from(i: i64) -> Self {
+        BencodeValue::Int(i)
+    }
+}
+impl From<&str> for BencodeValue {
+    fn from(s: &str) -> Self {
+        BencodeValue::String(serde_bytes::ByteBuf::from(s.as_bytes().to_vec()))
+    }
+}
+impl From<&[u8]> for BencodeValue {
+    fn from(b: &[u8]) -> Self {
+        BencodeValue::String(serde_bytes::ByteBuf::from(b.to_vec()))
+    }
+}
+impl From<Vec<u8>> for BencodeValue {
+    fn from(b: Vec<u8>) -> Self {
+        BencodeValue::String(serde_bytes::ByteBuf::from(b))
+    }
+}
+impl From<Vec<BencodeValue>> for BencodeValue {
+    fn from(l: Vec<BencodeValue>) -> Self {
+        BencodeValue::List(l)
+    }
+}
+
+// deeper than any real torrent or message, shallow enough not to blow the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum DecodeError {
+    #[error("unexpected end of input at byte {0}")]
+    UnexpectedEof(usize),
+    #[error("unexpected byte {byte:#04x} at byte {pos}")]
+    UnexpectedByte { byte: u8, pos: usize },
+    #[error("invalid integer at byte {0}")]
+    InvalidInteger(usize),
+    #[error("integer with leading zero at byte {0}")]
+    LeadingZero(usize),
+    #[error("dict key is not a byte string at byte {0}")]
+    NonStringKey(usize),
+    #[error("trailing data at byte {0}")]
+    TrailingData(usize),
+    #[error("values nested too deeply at byte {0}")]
+    TooDeep(usize),
+}
+
+/// Decodes a buffer that holds exactly one bencoded value.
+pub fn decode(buf: &[u8]) -> Result<BencodeValue, DecodeError> {
+    let (value, len) = decode_bencoded_value(buf)?;
+    if len != buf.len() {
+        return Err(DecodeError::TrailingData(len));
+    }
+    Ok(value)
+}
+
+/// Decodes the bencoded value at the start of `buf` and returns it together
+/// with the number of bytes it took up. Anything after it is left alone.
+pub fn decode_bencoded_value(buf: &[u8]) -> Result<(BencodeValue, usize), DecodeError> {
+    let mut decoder = Decoder { buf, pos: 0 };
+    let value = decoder.value(0)?;
+    Ok((value, decoder.pos))
+}
+
+/// Finds `key` in the top level dict of `buf` and returns the byte range its value
+/// occupies, so that it can be used (or hashed) exactly as it was encoded.
+pub fn dict_value_span(buf: &[u8], key: &[u8]) -> Result<Option<Range<usize>>, DecodeError> {
+    let mut decoder = Decoder { buf, pos: 0 };
+    match decoder.peek()? {
+        b'd' => decoder.pos += 1,
+        byte => return Err(DecodeError::UnexpectedByte { byte, pos: 0 }),
+    }
+    while decoder.peek()? != b'e' {
+        if !decoder.peek()?.is_ascii_digit() {
+            return Err(DecodeError::NonStringKey(decoder.pos));
+        }
+        let k = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value(1)?;
+        if k == key {
+            return Ok(Some(start..decoder.pos));
+        }
+    }
+    Ok(None)
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or(DecodeError::UnexpectedEof(self.pos))
+    }
 
-            (
-                BencodeValue::Int(num_bytes.parse::<i64>().unwrap()),
-                end_idx + 1,
-            )
+    fn value(&mut self, depth: usize) -> Result<BencodeValue, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep(self.pos));
         }
-        ('0'..='9', encoded) => {
-            let mut step = 0;
-            let mut colon_index = 0;
-            let mut num_bytes = String::new();
-            while step < encoded.len() {
-                if encoded[step] == ':' as u8 {
-                    colon_index = step;
-                    break;
-                } else {
-                    num_bytes.push(encoded[step] as char);
+        match self.peek()? {
+            b'd' => {
+                self.pos += 1;
+                let mut vals = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(DecodeError::NonStringKey(self.pos));
+                    }
+                    let key = self.bytes()?;
+                    let val = self.value(depth + 1)?;
+                    vals.insert(serde_bytes::ByteBuf::from(key.to_vec()), val);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::Map(vals))
+            }
+            b'l' => {
+                self.pos += 1;
+                let mut vals = Vec::new();
+                while self.peek()? != b'e' {
+                    vals.push(self.value(depth + 1)?);
                 }
-                step += 1;
+                self.pos += 1;
+                Ok(BencodeValue::List(vals))
+            }
+            b'i' => {
+                self.pos += 1;
+                let start = self.pos;
+                let digits = self.until(b'e')?;
+                Ok(BencodeValue::Int(parse_int(digits, start)?))
             }
-            let number = num_bytes.parse::<i64>().unwrap();
-            let string = &encoded[colon_index + 1..colon_index + 1 + number as usize];
-            let idx = colon_index + number as usize + 1;
-            (
-                BencodeValue::String(serde_bytes::ByteBuf::from(string.to_owned())),
-                idx,
-            )
+            b'0'..=b'9' => Ok(BencodeValue::String(serde_bytes::ByteBuf::from(
+                self.bytes()?.to_vec(),
+            ))),
+            byte => Err(DecodeError::UnexpectedByte {
+                byte,
+                pos: self.pos,
+            }),
         }
-        _ => {
-            panic!("Unhandled encoded value: {:#?}", encoded_value.clone())
+    }
+
+    /// Reads a `<length>:<bytes>` string.
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.pos;
+        let digits = self.until(b':')?;
+        let len = parse_int(digits, start)?;
+        if len < 0 {
+            return Err(DecodeError::InvalidInteger(start));
+        }
+        let len = len as usize;
+        if self.buf.len() - self.pos < len {
+            return Err(DecodeError::UnexpectedEof(self.buf.len()));
         }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Returns everything up to `end` and moves past it.
+    fn until(&mut self, end: u8) -> Result<&'a [u8], DecodeError> {
+        let start = self.pos;
+        let len = self.buf[start..]
+            .iter()
+            .position(|&b| b == end)
+            .ok_or(DecodeError::UnexpectedEof(self.buf.len()))?;
+        self.pos = start + len + 1;
+        Ok(&self.buf[start..start + len])
+    }
+}
+
+fn parse_int(digits: &[u8], pos: usize) -> Result<i64, DecodeError> {
+    let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+    if unsigned.is_empty() || !unsigned.iter().all(u8::is_ascii_digit) {
+        return Err(DecodeError::InvalidInteger(pos));
+    }
+    if unsigned[0] == b'0' && (unsigned.len() > 1 || unsigned.len() != digits.len()) {
+        // "-0" is just as illegal as "03"
+        return Err(DecodeError::LeadingZero(pos));
     }
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or(DecodeError::InvalidInteger(pos))
 }
 
 #[allow(dead_code)]
 pub fn debencode(val: String) -> serde_json::Value {
-    let v = decode_bencoded_value(val.into_bytes()).0;
+    let v = decode(val.as_bytes()).expect("invalid bencode");
     v.serialize()
 }
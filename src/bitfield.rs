@@ -0,0 +1,136 @@
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+pub enum BitfieldError {
+    #[error("bitfield of {got} bytes for {len} pieces")]
+    WrongLength { got: usize, len: usize },
+    #[error("bitfield has spare bits set")]
+    SpareBits,
+}
+
+/// One bit per piece, packed the way the bitfield message has it: high bit
+/// of the first byte is piece 0. Bits past `len` are always zero.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+impl Bitfield {
+    /// `len` bits, none of them set.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// `len` bits, all of them set.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self {
+            bytes: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        if !len.is_multiple_of(8) {
+            bitfield.bytes[len / 8] = 0xff << (8 - len % 8);
+        }
+        bitfield
+    }
+
+    /// Takes a bitfield off the wire. It has to be exactly as long as `len`
+    /// bits need, with the spare bits at the end cleared.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, BitfieldError> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(BitfieldError::WrongLength {
+                got: bytes.len(),
+                len,
+            });
+        }
+        if !len.is_multiple_of(8) && bytes[len / 8] & (0xff >> (len % 8)) != 0 {
+            return Err(BitfieldError::SpareBits);
+        }
+        Ok(Self {
+            bytes: bytes.to_vec(),
+            len,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// False for anything past the end.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < self.len, "bit {} of {}", index, self.len);
+        match value {
+            true => self.bytes[index / 8] |= 0x80 >> (index % 8),
+            false => self.bytes[index / 8] &= !(0x80 >> (index % 8)),
+        }
+    }
+
+    /// How many bits are set.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// The indexes of the bits that are set.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.get(index))
+    }
+
+    /// The indexes of the bits that aren't set.
+    pub fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| !self.get(index))
+    }
+
+    /// What's set both here and in `other`.
+    pub fn and(&self, other: &Bitfield) -> Bitfield {
+        let bytes = self
+            .bytes
+            .iter()
+            .zip(other.bytes.iter().chain(std::iter::repeat(&0)))
+            .map(|(a, b)| a & b)
+            .collect();
+        Self {
+            bytes,
+            len: self.len,
+        }
+    }
+
+    /// What's set here but not in `other`, e.g. pieces a peer has that we don't.
+    pub fn and_not(&self, other: &Bitfield) -> Bitfield {
+        let bytes = self
+            .bytes
+            .iter()
+            .zip(other.bytes.iter().chain(std::iter::repeat(&0)))
+            .map(|(a, b)| a & !b)
+            .collect();
+        Self {
+            bytes,
+            len: self.len,
+        }
+    }
+}
+impl From<&[bool]> for Bitfield {
+    fn from(bits: &[bool]) -> Self {
+        let mut bitfield = Bitfield::new(bits.len());
+        for (index, _) in bits.iter().enumerate().filter(|(_, &bit)| bit) {
+            bitfield.set(index, true);
+        }
+        bitfield
+    }
+}
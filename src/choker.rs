@@ -0,0 +1,129 @@
+use crate::scheduler::PeerKey;
+use rand::seq::IteratorRandom;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// peers unchoked for their rate, the optimistic unchoke comes on top
+pub const UNCHOKE_SLOTS: usize = 4;
+// how often the unchoked peers are picked again
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// how long the optimistic unchoke stays with one peer
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+struct PeerStats {
+    interested: bool,
+    // bytes since the last rechoke, every peer had the same time to
+    // collect them so they rank the same as a rate would
+    uploaded: u64,
+}
+
+/// Decides which peers we upload to. The ones that took the most lately get a
+/// slot, plus one picked at random every so often so new peers get a chance
+/// to show what they've got. We only upload from the server, which never
+/// downloads, so there is nothing to rank by but upload.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    peers: HashMap<PeerKey, PeerStats>,
+    next_key: PeerKey,
+    unchoked: HashSet<PeerKey>,
+    // the optimistic unchoke, and since when it has been
+    optimistic: Option<(PeerKey, Instant)>,
+}
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            peers: HashMap::new(),
+            next_key: 0,
+            unchoked: HashSet::new(),
+            optimistic: None,
+        }
+    }
+
+    /// A new peer. It's choked until a rechoke says otherwise.
+    pub fn add_peer(&mut self) -> PeerKey {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.peers.insert(key, PeerStats::default());
+        key
+    }
+
+    pub fn remove_peer(&mut self, key: PeerKey) {
+        self.peers.remove(&key);
+        self.unchoked.remove(&key);
+        if self
+            .optimistic
+            .is_some_and(|(optimistic, _)| optimistic == key)
+        {
+            self.optimistic = None;
+        }
+    }
+
+    /// Returns true if the peer got unchoked right away, because a slot was free.
+    pub fn set_interested(&mut self, key: PeerKey, interested: bool) -> bool {
+        let Some(stats) = self.peers.get_mut(&key) else {
+            return false;
+        };
+        stats.interested = interested;
+        // no need to keep a newcomer waiting for the next rechoke, the free
+        // slots are the regular ones plus the optimistic one
+        if interested && self.unchoked.len() < self.slots + 1 && !self.unchoked.contains(&key) {
+            self.unchoked.insert(key);
+            return true;
+        }
+        false
+    }
+
+    pub fn uploaded(&mut self, key: PeerKey, bytes: usize) {
+        if let Some(stats) = self.peers.get_mut(&key) {
+            stats.uploaded += bytes as u64;
+        }
+    }
+
+    pub fn is_unchoked(&self, key: PeerKey) -> bool {
+        self.unchoked.contains(&key)
+    }
+
+    /// Picks the unchoked peers again, by how fast they take what we send.
+    /// Returns true if anything changed.
+    pub fn rechoke(&mut self, now: Instant) -> bool {
+        let mut interested: Vec<(PeerKey, u64)> = self
+            .peers
+            .iter()
+            .filter(|(_, stats)| stats.interested)
+            .map(|(&key, stats)| (key, stats.uploaded))
+            .collect();
+        interested.sort_by_key(|&(key, bytes)| (std::cmp::Reverse(bytes), key));
+        let mut unchoked: HashSet<PeerKey> = interested
+            .iter()
+            .take(self.slots)
+            .map(|&(key, _)| key)
+            .collect();
+
+        let keep_optimistic = self.optimistic.is_some_and(|(key, since)| {
+            now.saturating_duration_since(since) < OPTIMISTIC_INTERVAL
+                && self.peers.get(&key).is_some_and(|stats| stats.interested)
+                && !unchoked.contains(&key)
+        });
+        if !keep_optimistic {
+            self.optimistic = interested
+                .iter()
+                .map(|&(key, _)| key)
+                .filter(|key| !unchoked.contains(key))
+                .choose(&mut rand::thread_rng())
+                .map(|key| (key, now));
+        }
+        if let Some((key, _)) = self.optimistic {
+            unchoked.insert(key);
+        }
+
+        for stats in self.peers.values_mut() {
+            stats.uploaded = 0;
+        }
+        let changed = unchoked != self.unchoked;
+        self.unchoked = unchoked;
+        changed
+    }
+}
@@ -0,0 +1,895 @@
+use crate::bencode::{self, BencodeValue};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+
+// nodes per bucket, and how many closest nodes a lookup ends up with
+pub const K: usize = 8;
+// queries a lookup keeps in flight at once
+const ALPHA: usize = 3;
+// how long a node gets to answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// nodes that missed this many queries in a row make room for new ones
+const MAX_FAILURES: u32 = 2;
+// tokens are good for one to two of these
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+// peers that don't announce again within this are forgotten
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// most peers handed out for one torrent, so the answer fits in a packet
+const MAX_VALUES: usize = 50;
+// most peers kept for one torrent
+const MAX_STORED_PEERS: usize = 500;
+// the udp port we try for the DHT first
+pub const DHT_PORT: u16 = 6881;
+// well known nodes to join through when we know nobody
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// KRPC error codes
+const PROTOCOL_ERROR: i64 = 203;
+const METHOD_UNKNOWN: i64 = 204;
+
+/// A node id, or an info hash seen as a point in the same space.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// The XOR metric. Compared as arrays, it orders like the 160 bit number.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    /// How many leading bits the two ids share, 160 if they're the same.
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        match distance.iter().position(|&byte| byte != 0) {
+            Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+            None => 160,
+        }
+    }
+}
+
+/// Another DHT node, and where it is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+#[derive(Debug)]
+struct Entry {
+    node: Node,
+    failures: u32,
+}
+
+/// The nodes we know, in k-buckets. Bucket `i` holds the nodes that share
+/// exactly `i` leading bits with our id, the same thing a fully split
+/// Kademlia table ends up with. Nodes that were heard from last go to the end.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Adds a node we just heard from, or moves it to the end of its bucket.
+    /// A full bucket only takes it in place of a node that stopped answering,
+    /// nodes that have been around for a while tend to stay. Returns false
+    /// if there was no room.
+    pub fn insert(&mut self, node: Node) -> bool {
+        if node.id == self.id {
+            return false;
+        }
+        let bucket = &mut self.buckets[self.id.common_prefix(&node.id).min(159)];
+        if let Some(i) = bucket.iter().position(|e| e.node.id == node.id) {
+            bucket.remove(i);
+        } else if bucket.len() >= K {
+            match bucket.iter().position(|e| e.failures >= MAX_FAILURES) {
+                Some(i) => {
+                    bucket.remove(i);
+                }
+                None => return false,
+            }
+        }
+        bucket.push(Entry { node, failures: 0 });
+        true
+    }
+
+    /// Counts a query to `addr` that went unanswered.
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// Up to `n` nodes closest to `target`, leaving out ones that stopped answering.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// Every node that still answers.
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|e| e.failures < MAX_FAILURES)
+            .map(|e| e.node)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+/// A KRPC query (BEP 5).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode(NodeId),
+    GetPeers([u8; 20]),
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        // the peer is on the port the query came from, e.g. behind a NAT
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    // answered with an error
+    Unknown(String),
+}
+
+/// What a node answers. Which fields are filled in depends on the query.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<Node>,
+    pub values: Vec<SocketAddrV4>,
+    pub token: Option<Vec<u8>>,
+}
+
+/// One KRPC message. `t` is the transaction id, answers carry the query's.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Krpc {
+    Query {
+        t: Vec<u8>,
+        id: NodeId,
+        query: Query,
+    },
+    Response {
+        t: Vec<u8>,
+        response: Response,
+    },
+    Error {
+        t: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+impl Krpc {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Krpc::Query { t, id, query } => {
+                let mut args = vec![("id", BencodeValue::from(&id.0[..]))];
+                let method = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode(target) => {
+                        args.push(("target", BencodeValue::from(&target.0[..])));
+                        "find_node"
+                    }
+                    Query::GetPeers(info_hash) => {
+                        args.push(("info_hash", BencodeValue::from(&info_hash[..])));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.push(("info_hash", BencodeValue::from(&info_hash[..])));
+                        args.push(("port", BencodeValue::from(*port as i64)));
+                        args.push(("token", BencodeValue::from(&token[..])));
+                        if *implied_port {
+                            args.push(("implied_port", BencodeValue::from(1)));
+                        }
+                        "announce_peer"
+                    }
+                    Query::Unknown(method) => method,
+                };
+                BencodeValue::dict([
+                    ("t", BencodeValue::from(&t[..])),
+                    ("y", BencodeValue::from("q")),
+                    ("q", BencodeValue::from(method)),
+                    ("a", BencodeValue::dict(args)),
+                ])
+            }
+            Krpc::Response { t, response } => {
+                let mut r = vec![("id", BencodeValue::from(&response.id.0[..]))];
+                if !response.nodes.is_empty() {
+                    r.push(("nodes", BencodeValue::from(compact_nodes(&response.nodes))));
+                }
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .map(|addr| BencodeValue::from(&compact_addr(addr)[..]))
+                        .collect::<Vec<_>>();
+                    r.push(("values", BencodeValue::from(values)));
+                }
+                if let Some(token) = &response.token {
+                    r.push(("token", BencodeValue::from(&token[..])));
+                }
+                BencodeValue::dict([
+                    ("t", BencodeValue::from(&t[..])),
+                    ("y", BencodeValue::from("r")),
+                    ("r", BencodeValue::dict(r)),
+                ])
+            }
+            Krpc::Error { t, code, message } => BencodeValue::dict([
+                ("t", BencodeValue::from(&t[..])),
+                ("y", BencodeValue::from("e")),
+                (
+                    "e",
+                    BencodeValue::from(vec![
+                        BencodeValue::from(*code),
+                        BencodeValue::from(message.as_str()),
+                    ]),
+                ),
+            ]),
+        }
+        .encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let msg = bencode::decode(bytes)?;
+        let bytes = |dict: &BencodeValue, key| {
+            dict.get(key)
+                .and_then(BencodeValue::as_bytes)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| anyhow::anyhow!("KRPC message without {}", key))
+        };
+        let hash = |dict: &BencodeValue, key| -> anyhow::Result<[u8; 20]> {
+            bytes(dict, key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("KRPC {} is not 20 bytes", key))
+        };
+        let t = bytes(&msg, "t")?;
+
+        match bytes(&msg, "y")?.as_slice() {
+            b"q" => {
+                let args = msg
+                    .get("a")
+                    .ok_or_else(|| anyhow::anyhow!("KRPC query without arguments"))?;
+                let query = match bytes(&msg, "q")?.as_slice() {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode(NodeId(hash(args, "target")?)),
+                    b"get_peers" => Query::GetPeers(hash(args, "info_hash")?),
+                    b"announce_peer" => {
+                        let implied_port =
+                            args.get("implied_port").and_then(BencodeValue::as_int) == Some(1);
+                        let port = args.get("port").and_then(BencodeValue::as_int);
+                        Query::AnnouncePeer {
+                            info_hash: hash(args, "info_hash")?,
+                            port: match (port.and_then(|p| u16::try_from(p).ok()), implied_port) {
+                                (Some(port), _) => port,
+                                (None, true) => 0,
+                                (None, false) => anyhow::bail!("announce_peer without port"),
+                            },
+                            implied_port,
+                            token: bytes(args, "token")?,
+                        }
+                    }
+                    method => Query::Unknown(String::from_utf8_lossy(method).into_owned()),
+                };
+                Ok(Krpc::Query {
+                    t,
+                    id: NodeId(hash(args, "id")?),
+                    query,
+                })
+            }
+            b"r" => {
+                let r = msg
+                    .get("r")
+                    .ok_or_else(|| anyhow::anyhow!("KRPC response without r"))?;
+                let nodes = match r.get("nodes").and_then(BencodeValue::as_bytes) {
+                    Some(nodes) => parse_nodes(nodes)?,
+                    None => Vec::new(),
+                };
+                // peers that aren't 6 bytes are skipped, some nodes send IPv6 ones
+                let values = match r.get("values") {
+                    Some(BencodeValue::List(values)) => values
+                        .iter()
+                        .filter_map(BencodeValue::as_bytes)
+                        .filter(|value| value.len() == 6)
+                        .map(parse_addr)
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                let response = Response {
+                    id: NodeId(hash(r, "id")?),
+                    nodes,
+                    values,
+                    token: r
+                        .get("token")
+                        .and_then(BencodeValue::as_bytes)
+                        .map(<[u8]>::to_vec),
+                };
+                Ok(Krpc::Response { t, response })
+            }
+            b"e" => {
+                let (code, message) = match msg.get("e") {
+                    Some(BencodeValue::List(e)) => (
+                        e.first().and_then(BencodeValue::as_int).unwrap_or(0),
+                        e.get(1)
+                            .and_then(BencodeValue::as_bytes)
+                            .map(|m| String::from_utf8_lossy(m).into_owned())
+                            .unwrap_or_default(),
+                    ),
+                    _ => (0, String::new()),
+                };
+                Ok(Krpc::Error { t, code, message })
+            }
+            y => anyhow::bail!("unknown KRPC message type {:?}", String::from_utf8_lossy(y)),
+        }
+    }
+}
+
+fn compact_addr(addr: &SocketAddrV4) -> [u8; 6] {
+    let mut out = [0; 6];
+    out[..4].copy_from_slice(&addr.ip().octets());
+    out[4..].copy_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+fn parse_addr(bytes: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    )
+}
+
+/// Nodes packed as 20 bytes of id and 6 of address each.
+fn compact_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(26 * nodes.len());
+    for node in nodes {
+        out.extend_from_slice(&node.id.0);
+        out.extend_from_slice(&compact_addr(&node.addr));
+    }
+    out
+}
+
+fn parse_nodes(bytes: &[u8]) -> anyhow::Result<Vec<Node>> {
+    if !bytes.len().is_multiple_of(26) {
+        anyhow::bail!("compact nodes of {} bytes", bytes.len());
+    }
+    Ok(bytes
+        .chunks(26)
+        .map(|chunk| Node {
+            id: NodeId(chunk[..20].try_into().unwrap()),
+            addr: parse_addr(&chunk[20..]),
+        })
+        .collect())
+}
+
+/// Hands out announce tokens: a hash of the asker's ip and a secret that
+/// changes every TOKEN_ROTATION. Tokens from the secret before are still good.
+struct Tokens {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+impl Tokens {
+    fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated = now;
+        }
+    }
+
+    fn token(secret: &[u8; 20], ip: &Ipv4Addr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(ip.octets());
+        hasher.finalize().to_vec()
+    }
+
+    fn check(&self, ip: &Ipv4Addr, token: &[u8]) -> bool {
+        token == Self::token(&self.secret, ip) || token == Self::token(&self.previous, ip)
+    }
+}
+
+/// The id and the good nodes of a DHT node, saved between runs so the next
+/// start doesn't have to join through the bootstrap nodes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<Node>,
+}
+impl DhtState {
+    pub fn encode(&self) -> Vec<u8> {
+        BencodeValue::dict([
+            ("id", BencodeValue::from(&self.id.0[..])),
+            ("nodes", BencodeValue::from(compact_nodes(&self.nodes))),
+        ])
+        .encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let dict = bencode::decode(bytes)?;
+        let id = dict
+            .get("id")
+            .and_then(BencodeValue::as_bytes)
+            .and_then(|id| <[u8; 20]>::try_from(id).ok())
+            .ok_or_else(|| anyhow::anyhow!("DHT state has no id"))?;
+        let nodes = dict
+            .get("nodes")
+            .and_then(BencodeValue::as_bytes)
+            .ok_or_else(|| anyhow::anyhow!("DHT state has no nodes"))?;
+        Ok(Self {
+            id: NodeId(id),
+            nodes: parse_nodes(nodes)?,
+        })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::decode(&std::fs::read(path)?)
+    }
+
+    /// Writes the file next to its final place first, like the resume file.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.encode())?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// Where the DHT state is kept, shared by every download.
+pub fn state_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".jab-dht")
+}
+
+/// What a lookup found: the closest nodes that answered, with the tokens
+/// they gave us, and any peers on the way.
+#[derive(Debug, Default)]
+struct Lookup {
+    closest: Vec<(Node, Option<Vec<u8>>)>,
+    values: Vec<SocketAddrV4>,
+}
+
+// where the answer to one of our queries goes
+type Answer = oneshot::Sender<anyhow::Result<Response>>;
+// peers announced to us and when, by info hash
+type PeerStore = HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>;
+
+/// What the node's socket task and the lookups share.
+struct Inner {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    // our queries that wait for an answer, by transaction id
+    pending: Mutex<HashMap<Vec<u8>, (SocketAddrV4, Answer)>>,
+    next_t: AtomicU16,
+    peers: Mutex<PeerStore>,
+    tokens: Mutex<Tokens>,
+}
+impl Inner {
+    /// Sends `query` to `addr` and waits for the answer. A node that doesn't
+    /// answer in time gets a failure in the routing table.
+    async fn query(&self, addr: SocketAddrV4, query: Query) -> anyhow::Result<Response> {
+        let t = self
+            .next_t
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(t.clone(), (addr, tx));
+        let msg = Krpc::Query {
+            t: t.clone(),
+            id: self.id,
+            query,
+        };
+        let sent = self.socket.send_to(&msg.encode(), addr).await;
+        let answer = match sent {
+            Ok(_) => tokio::time::timeout(QUERY_TIMEOUT, rx).await.ok(),
+            Err(_) => None,
+        };
+        match answer {
+            Some(Ok(result)) => result,
+            _ => {
+                self.pending.lock().unwrap().remove(&t);
+                self.table.lock().unwrap().failed(addr);
+                anyhow::bail!("{} did not answer", addr)
+            }
+        }
+    }
+
+    /// Our answer to a query from `from`, or an error code and message.
+    fn answer(&self, from: SocketAddrV4, query: Query) -> Result<Response, (i64, &'static str)> {
+        let now = Instant::now();
+        let mut response = Response {
+            id: self.id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode(target) => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+            }
+            Query::GetPeers(info_hash) => {
+                let mut tokens = self.tokens.lock().unwrap();
+                tokens.rotate(now);
+                response.token = Some(Tokens::token(&tokens.secret, from.ip()));
+                response.values = self.stored_peers(&info_hash, now);
+                if response.values.is_empty() {
+                    response.nodes = self.table.lock().unwrap().closest(&NodeId(info_hash), K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                let mut tokens = self.tokens.lock().unwrap();
+                tokens.rotate(now);
+                if !tokens.check(from.ip(), &token) {
+                    return Err((PROTOCOL_ERROR, "bad token"));
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.store_peer(info_hash, SocketAddrV4::new(*from.ip(), port), now);
+            }
+            Query::Unknown(_) => return Err((METHOD_UNKNOWN, "method unknown")),
+        }
+        Ok(response)
+    }
+
+    fn store_peer(&self, info_hash: [u8; 20], addr: SocketAddrV4, now: Instant) {
+        let mut peers = self.peers.lock().unwrap();
+        let stored = peers.entry(info_hash).or_default();
+        stored.retain(|(other, since)| {
+            *other != addr && now.saturating_duration_since(*since) < PEER_TTL
+        });
+        if stored.len() >= MAX_STORED_PEERS {
+            stored.remove(0);
+        }
+        stored.push((addr, now));
+    }
+
+    /// The freshest peers announced for `info_hash`.
+    fn stored_peers(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddrV4> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(stored) = peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        stored.retain(|(_, since)| now.saturating_duration_since(*since) < PEER_TTL);
+        stored
+            .iter()
+            .rev()
+            .take(MAX_VALUES)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+/// Answers queries that come in on the socket, and hands answers to our own
+/// queries to whoever is waiting for them.
+async fn run(inner: Arc<Inner>) {
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let Ok((len, from)) = inner.socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        // there's nobody to tell about garbage
+        let Ok(msg) = Krpc::decode(&buf[..len]) else {
+            continue;
+        };
+        match msg {
+            Krpc::Query { t, id, query } => {
+                // a node that asks is alive, most of the time it can be reached too
+                inner.table.lock().unwrap().insert(Node { id, addr: from });
+                let reply = match inner.answer(from, query) {
+                    Ok(response) => Krpc::Response { t, response },
+                    Err((code, message)) => Krpc::Error {
+                        t,
+                        code,
+                        message: message.to_owned(),
+                    },
+                };
+                let _ = inner.socket.send_to(&reply.encode(), from).await;
+            }
+            Krpc::Response { t, response } => {
+                let Some(tx) = take_pending(&inner, &t, from) else {
+                    continue;
+                };
+                inner.table.lock().unwrap().insert(Node {
+                    id: response.id,
+                    addr: from,
+                });
+                let _ = tx.send(Ok(response));
+            }
+            Krpc::Error { t, code, message } => {
+                if let Some(tx) = take_pending(&inner, &t, from) {
+                    let _ = tx.send(Err(anyhow::anyhow!("error {}: {}", code, message)));
+                }
+            }
+        }
+    }
+}
+
+/// The query waiting for transaction `t`, if `from` is who it went to.
+fn take_pending(inner: &Inner, t: &[u8], from: SocketAddrV4) -> Option<Answer> {
+    let mut pending = inner.pending.lock().unwrap();
+    match pending.get(t) {
+        Some((addr, _)) if *addr == from => pending.remove(t).map(|(_, tx)| tx),
+        _ => None,
+    }
+}
+
+/// A mainline DHT node (BEP 5). It answers other nodes from a task of its
+/// own for as long as it lives, and finds peers for torrents without a tracker.
+pub struct Dht {
+    inner: Arc<Inner>,
+    task: JoinHandle<()>,
+    // where the routing table is saved, if anywhere
+    state_file: Option<PathBuf>,
+}
+impl Dht {
+    /// Starts a node with id `id` on `addr`.
+    pub async fn bind(addr: impl ToSocketAddrs, id: NodeId) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let inner = Arc::new(Inner {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_t: AtomicU16::new(rand::random()),
+            peers: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(Tokens::new(Instant::now())),
+        });
+        let task = tokio::spawn(run(inner.clone()));
+        Ok(Self {
+            inner,
+            task,
+            state_file: None,
+        })
+    }
+
+    /// Starts the node we use for downloads: on DHT_PORT if it's free, with
+    /// the id and nodes from the last run, then joins through `bootstrap`.
+    pub async fn start(bootstrap: &[String], state_file: PathBuf) -> anyhow::Result<Self> {
+        let state = DhtState::load(&state_file).ok();
+        let id = state.as_ref().map_or_else(NodeId::random, |state| state.id);
+        let dht = match Self::bind(("0.0.0.0", DHT_PORT), id).await {
+            Ok(dht) => dht,
+            Err(_) => Self::bind(("0.0.0.0", 0), id).await?,
+        };
+        let dht = dht.with_state_file(state_file);
+        for node in state.into_iter().flat_map(|state| state.nodes) {
+            dht.add_node(node);
+        }
+
+        let mut routers = Vec::new();
+        for host in bootstrap {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(addrs) => routers.extend(addrs.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => println!("could not resolve {}: {}", host, e),
+            }
+        }
+        dht.bootstrap(&routers).await?;
+        dht.save_state();
+        Ok(dht)
+    }
+
+    /// Saves the routing table to `path` whenever `save_state` is called.
+    pub fn with_state_file(mut self, path: PathBuf) -> Self {
+        self.state_file = Some(path);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    #[allow(dead_code)]
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// How many nodes the routing table has.
+    pub fn len(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    /// Puts a node in the routing table without asking it first, e.g. one
+    /// saved by an earlier run.
+    pub fn add_node(&self, node: Node) {
+        self.inner.table.lock().unwrap().insert(node);
+    }
+
+    /// Asks the node at `addr` for its id.
+    #[allow(dead_code)]
+    pub async fn ping(&self, addr: SocketAddrV4) -> anyhow::Result<NodeId> {
+        Ok(self.inner.query(addr, Query::Ping).await?.id)
+    }
+
+    /// Joins the network through `routers`, as well as the nodes we know
+    /// already, by looking up our own id.
+    pub async fn bootstrap(&self, routers: &[SocketAddrV4]) -> anyhow::Result<()> {
+        let mut pings = JoinSet::new();
+        for &addr in routers {
+            let inner = self.inner.clone();
+            pings.spawn(async move { inner.query(addr, Query::Ping).await });
+        }
+        while pings.join_next().await.is_some() {}
+        self.find_node(self.inner.id).await;
+        if self.len() == 0 {
+            anyhow::bail!("no DHT node answered");
+        }
+        Ok(())
+    }
+
+    /// The K nodes closest to `target` that answered.
+    pub async fn find_node(&self, target: NodeId) -> Vec<Node> {
+        let lookup = self.lookup(target, false).await;
+        lookup.closest.into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// Peers for `info_hash` that the nodes closest to it know about.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.lookup(NodeId(info_hash), true).await.values
+    }
+
+    /// Like `get_peers`, and then tells the closest nodes we're a peer too,
+    /// on `port`.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddrV4> {
+        let lookup = self.lookup(NodeId(info_hash), true).await;
+        let mut announces = JoinSet::new();
+        for (node, token) in lookup.closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let inner = self.inner.clone();
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port: false,
+                token,
+            };
+            announces.spawn(async move { inner.query(node.addr, query).await });
+        }
+        while announces.join_next().await.is_some() {}
+        lookup.values
+    }
+
+    pub fn state(&self) -> DhtState {
+        DhtState {
+            id: self.inner.id,
+            nodes: self.inner.table.lock().unwrap().nodes(),
+        }
+    }
+
+    /// Writes the routing table to the state file, if there is one.
+    pub fn save_state(&self) {
+        if let Some(path) = &self.state_file {
+            if let Err(e) = self.state().save(path) {
+                println!("error saving DHT state: {}", e);
+            }
+        }
+    }
+
+    /// The iterative Kademlia lookup: keeps asking the closest nodes we know
+    /// of that haven't been asked yet, ALPHA at a time, until the K closest
+    /// have all answered or given up. With `get_peers` it collects peers and
+    /// tokens on the way, otherwise it's a find_node.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut shortlist = self.inner.table.lock().unwrap().closest(&target, K);
+        let mut asked: HashSet<SocketAddrV4> = HashSet::new();
+        let mut lookup = Lookup::default();
+        let mut in_flight = JoinSet::new();
+        loop {
+            while in_flight.len() < ALPHA {
+                let Some(node) = shortlist
+                    .iter()
+                    .take(K)
+                    .find(|node| !asked.contains(&node.addr))
+                    .copied()
+                else {
+                    break;
+                };
+                asked.insert(node.addr);
+                let query = match get_peers {
+                    true => Query::GetPeers(target.0),
+                    false => Query::FindNode(target),
+                };
+                let inner = self.inner.clone();
+                in_flight.spawn(async move { (node, inner.query(node.addr, query).await) });
+            }
+            let Some(done) = in_flight.join_next().await else {
+                break;
+            };
+            let Ok((node, result)) = done else {
+                continue;
+            };
+            match result {
+                Ok(response) => {
+                    for addr in response.values {
+                        if !lookup.values.contains(&addr) {
+                            lookup.values.push(addr);
+                        }
+                    }
+                    for found in response.nodes {
+                        let known = shortlist.iter().any(|other| other.addr == found.addr);
+                        if !known && found.id != self.inner.id {
+                            shortlist.push(found);
+                        }
+                    }
+                    let node = Node {
+                        id: response.id,
+                        addr: node.addr,
+                    };
+                    lookup.closest.push((node, response.token));
+                }
+                Err(_) => shortlist.retain(|other| other.addr != node.addr),
+            }
+            shortlist.sort_by_key(|node| node.id.distance(&target));
+        }
+        lookup
+            .closest
+            .sort_by_key(|(node, _)| node.id.distance(&target));
+        lookup.closest.truncate(K);
+        lookup
+    }
+}
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,173 @@
+use crate::bencode::{self, BencodeValue};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The dict peers swap as extended message 0 (BEP 10).
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ExtendedHandshake {
+    // extension name -> the id the sender wants to receive it as
+    pub m: BTreeMap<String, u8>,
+    // client name and version
+    pub v: Option<String>,
+    // tcp port the sender listens on
+    pub p: Option<u16>,
+    // how many outstanding requests the sender allows
+    pub reqq: Option<u32>,
+    // size of the info dict, for ut_metadata
+    pub metadata_size: Option<u64>,
+    // our address as the sender sees it
+    pub yourip: Option<IpAddr>,
+}
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let m = BencodeValue::dict(
+            self.m
+                .iter()
+                .map(|(name, id)| (name.as_str(), BencodeValue::from(*id as i64))),
+        );
+        let mut entries = vec![("m", m)];
+        if let Some(v) = &self.v {
+            entries.push(("v", BencodeValue::from(v.as_str())));
+        }
+        if let Some(p) = self.p {
+            entries.push(("p", BencodeValue::from(p as i64)));
+        }
+        if let Some(reqq) = self.reqq {
+            entries.push(("reqq", BencodeValue::from(reqq as i64)));
+        }
+        if let Some(size) = self.metadata_size {
+            entries.push(("metadata_size", BencodeValue::from(size as i64)));
+        }
+        match self.yourip {
+            Some(IpAddr::V4(ip)) => entries.push(("yourip", BencodeValue::from(&ip.octets()[..]))),
+            Some(IpAddr::V6(ip)) => entries.push(("yourip", BencodeValue::from(&ip.octets()[..]))),
+            None => {}
+        }
+        BencodeValue::dict(entries).encode()
+    }
+
+    /// Parses a handshake. Entries with the wrong type are ignored rather than
+    /// failing the whole handshake, clients disagree on a lot of these.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let dict = bencode::decode(bytes)?;
+        if !matches!(dict, BencodeValue::Map(_)) {
+            anyhow::bail!("extended handshake is not a dict");
+        }
+
+        let mut m = BTreeMap::new();
+        if let Some(BencodeValue::Map(entries)) = dict.get("m") {
+            for (name, id) in entries {
+                let name = String::from_utf8_lossy(name).into_owned();
+                match id.as_int() {
+                    Some(id @ 0..=255) => {
+                        m.insert(name, id as u8);
+                    }
+                    _ => continue,
+                }
+            }
+        }
+        let int = |key| dict.get(key).and_then(BencodeValue::as_int);
+        Ok(Self {
+            m,
+            v: dict
+                .get("v")
+                .and_then(BencodeValue::as_bytes)
+                .map(|v| String::from_utf8_lossy(v).into_owned()),
+            p: int("p").and_then(|p| u16::try_from(p).ok()),
+            reqq: int("reqq").and_then(|r| u32::try_from(r).ok()),
+            metadata_size: int("metadata_size").and_then(|s| u64::try_from(s).ok()),
+            yourip: dict
+                .get("yourip")
+                .and_then(BencodeValue::as_bytes)
+                .and_then(|ip| match ip.len() {
+                    4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?))),
+                    16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?))),
+                    _ => None,
+                }),
+        })
+    }
+
+    /// Applies a later handshake from the same peer. An id of 0 turns an extension off.
+    pub fn update(&mut self, other: ExtendedHandshake) {
+        for (name, id) in other.m {
+            match id {
+                0 => self.m.remove(&name),
+                id => self.m.insert(name, id),
+            };
+        }
+        self.v = other.v.or(self.v.take());
+        self.p = other.p.or(self.p);
+        self.reqq = other.reqq.or(self.reqq);
+        self.metadata_size = other.metadata_size.or(self.metadata_size);
+        self.yourip = other.yourip.or(self.yourip);
+    }
+}
+
+/// Something that speaks one extension, e.g. ut_metadata. Handlers are
+/// registered on a `Peer` and get every message the peer sends for them.
+pub trait ExtensionHandler: Send {
+    /// The name the extension goes by in the `m` dict.
+    fn name(&self) -> &'static str;
+
+    /// Fills in any handshake fields that belong to this extension.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called whenever the peer sends its extended handshake.
+    fn on_handshake(&mut self, _remote: &ExtendedHandshake) {}
+
+    /// Handles a message for this extension. Whatever comes back is sent to
+    /// the peer as messages of the same extension.
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>;
+}
+
+/// The extensions we support on a connection. A handler's local id is its
+/// position in the registry plus one, since 0 is the handshake.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+}
+impl ExtensionRegistry {
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) -> u8 {
+        match self.local_id(handler.name()) {
+            Some(id) => {
+                self.handlers[id as usize - 1] = handler;
+                id
+            }
+            None => {
+                assert!(self.handlers.len() < 255, "too many extensions");
+                self.handlers.push(handler);
+                self.handlers.len() as u8
+            }
+        }
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.handlers
+            .iter()
+            .position(|h| h.name() == name)
+            .map(|i| i as u8 + 1)
+    }
+
+    /// Our side of the extended handshake.
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake::default();
+        for (i, handler) in self.handlers.iter().enumerate() {
+            handshake.m.insert(handler.name().to_owned(), i as u8 + 1);
+            handler.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    pub fn on_handshake(&mut self, remote: &ExtendedHandshake) {
+        for handler in self.handlers.iter_mut() {
+            handler.on_handshake(remote);
+        }
+    }
+
+    pub fn get_mut(&mut self, local_id: u8) -> Option<&mut Box<dyn ExtensionHandler>> {
+        match local_id {
+            0 => None,
+            id => self.handlers.get_mut(id as usize - 1),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use thiserror::Error;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum MagnetError {
+    #[error("not a magnet link")]
+    NotAMagnet,
+    #[error("magnet link has no btih info hash")]
+    MissingInfoHash,
+    #[error("invalid info hash {0:?}")]
+    InvalidInfoHash(String),
+    #[error("invalid percent encoding in {0:?}")]
+    InvalidEncoding(String),
+}
+
+/// A parsed `magnet:?xt=urn:btih:...` link.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    // dn, a name to show until we have the metadata
+    pub display_name: Option<String>,
+    // tr, tracker urls
+    pub trackers: Vec<String>,
+    // x.pe, peers as host:port
+    pub peers: Vec<String>,
+}
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or(MagnetError::NotAMagnet)?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for param in query.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            // clients number repeated keys sometimes, e.g. tr.1=...&tr.2=...
+            let key = match key.split_once('.') {
+                Some((key, n)) if n.chars().all(|c| c.is_ascii_digit()) => key,
+                _ => key,
+            };
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(percent_decode(&value.replace('+', " "))?),
+                "tr" => trackers.push(percent_decode(value)?),
+                "x.pe" => peers.push(percent_decode(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+fn percent_decode(value: &str) -> Result<String, MagnetError> {
+    urlencoding::decode(value)
+        .map(|s| s.into_owned())
+        .map_err(|_| MagnetError::InvalidEncoding(value.to_owned()))
+}
+
+/// The info hash is either 40 hex characters or 32 base32 characters.
+fn parse_btih(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let invalid = || MagnetError::InvalidInfoHash(hash.to_owned());
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|_| invalid())?,
+        32 => base32_decode(hash).ok_or_else(invalid)?,
+        _ => return Err(invalid()),
+    };
+    bytes.try_into().map_err(|_| invalid())
+}
+
+// RFC 4648 base32, without padding
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
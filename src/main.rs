@@ -1,13 +1,28 @@
 use crate::client::Client;
-use crate::peer::Peer;
-use crate::torrent::{Torrent, TorrentFile};
+use crate::dht::{Dht, BOOTSTRAP_NODES};
+use crate::peer::{Peer, PEER_ID};
+use crate::torrent::{Torrent, TorrentFile, TorrentState};
 use clap::Parser;
-use hex;
+use std::sync::Arc;
 mod bencode;
+mod bitfield;
+mod choker;
 mod client;
+mod dht;
+mod extension;
+mod magnet;
+mod message;
+mod metadata;
 mod peer;
+mod pex;
+mod resume;
+mod scheduler;
+mod server;
+mod storage;
+mod swarm;
 mod tests;
 mod torrent;
+mod tracker;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -42,26 +57,91 @@ enum Command {
         #[clap(short = 'o')]
         target_filename: String,
         torrent: String,
+        /// most block requests to keep out with a single peer
+        #[clap(long)]
+        max_requests: Option<usize>,
+        /// download pieces in order, e.g. to play the file while it downloads
+        #[clap(long)]
+        sequential: bool,
+        /// map the target files into memory instead of writing to them
+        #[clap(long)]
+        mmap: bool,
+        /// keep uploading to other peers once the download is done
+        #[clap(long)]
+        seed: bool,
+        #[clap(flatten)]
+        dht: DhtArgs,
     },
+    Seed {
+        /// where the download is, like `-o` of `download`
+        target_filename: String,
+        torrent: String,
+        #[clap(flatten)]
+        dht: DhtArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct DhtArgs {
+    /// don't look for peers on the DHT
+    #[clap(long)]
+    no_dht: bool,
+    /// a DHT node to join through, instead of the well known ones
+    #[clap(long = "dht-node")]
+    dht_nodes: Vec<String>,
+}
+impl DhtArgs {
+    /// Starts the DHT node, unless it's turned off or nobody answers.
+    async fn start(self) -> Option<Arc<Dht>> {
+        if self.no_dht {
+            return None;
+        }
+        let nodes = match self.dht_nodes.is_empty() {
+            true => BOOTSTRAP_NODES.iter().map(|n| n.to_string()).collect(),
+            false => self.dht_nodes,
+        };
+        match Dht::start(&nodes, dht::state_path()).await {
+            Ok(dht) => Some(Arc::new(dht)),
+            Err(e) => {
+                println!("DHT: {}", e);
+                None
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
     match args.command {
-        Command::Decode { value } => {
-            let decoded_value = bencode::decode_bencoded_value(value.into_bytes()).0;
-            println!("{}", decoded_value.serialize());
-        }
+        Command::Decode { value } => match bencode::decode(value.as_bytes()) {
+            Ok(decoded_value) => println!("{}", decoded_value.serialize()),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
         Command::Info { torrent } => {
             let file: Vec<u8> = std::fs::read(&torrent).unwrap();
-            let torrent: TorrentFile = serde_bencode::from_bytes(&file).unwrap();
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
+            let torrent = TorrentFile::from_bytes(&file).unwrap();
+            if let Some(announce) = &torrent.announce {
+                println!("Tracker URL: {}", announce);
+            }
+            if let Some(tiers) = &torrent.announce_list {
+                for (i, tier) in tiers.iter().enumerate() {
+                    println!("Tier {}: {}", i, tier.join(" "));
+                }
+            }
+            println!("Length: {}", torrent.info.total_length());
+            if let Some(files) = &torrent.info.files {
+                println!("Files:");
+                for file in files {
+                    println!("{} {}", file.path.join("/"), file.length);
+                }
+            }
 
             // hash
-            let hash = torrent.info.hash();
-            println!("Info Hash: {}", hex::encode(hash));
+            println!("Info Hash: {}", hex::encode(torrent.info_hash));
 
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Piece Hashes:");
@@ -78,12 +158,11 @@ async fn main() {
             hashes.push(peices);
         }
         Command::Peers { torrent } => {
-            let torrent: Torrent = Torrent::from_file(torrent);
-            let info_hash = torrent.torrent_file.info.hash();
+            let mut torrent: Torrent = Torrent::from_file(torrent);
             let peer_ips = torrent.peer_ips().await;
 
             for peer in peer_ips {
-                println!("{}", peer.to_string());
+                println!("{}", peer);
             }
         }
 
@@ -93,9 +172,11 @@ async fn main() {
             peer_string,
         } => {
             let torrent: Torrent = Torrent::from_file(torrent);
-            let peer_id = b"00112233445566778899".to_owned();
             let mut peer = Peer::new(peer_string).await;
-            let handshake = peer.handshake(&torrent.torrent_file, peer_id).await;
+            let handshake = peer
+                .handshake(torrent.torrent_file.info_hash, PEER_ID)
+                .await
+                .unwrap();
 
             println!("Peer ID: {}", hex::encode(handshake.peer_id));
         }
@@ -106,9 +187,13 @@ async fn main() {
             torrent,
             index,
         } => {
-            let mut client = Client::from_torrent_file(torrent).await;
+            let mut client = Client::from_torrent_file(torrent);
 
-            client.torrent.download_piece(index, filename).await;
+            client
+                .torrent
+                .download_piece(index, filename)
+                .await
+                .unwrap();
 
             // let x = client.dl_loop().await.unwrap();
             // println!("{:#?}", x);
@@ -116,10 +201,46 @@ async fn main() {
         Command::Download {
             target_filename,
             torrent,
+            max_requests,
+            sequential,
+            mmap,
+            seed,
+            dht,
         } => {
-            let mut client = Client::from_torrent_file(torrent).await;
+            let dht = dht.start().await;
+            let mut client = if torrent.starts_with("magnet:") {
+                Client::from_magnet(&torrent, dht).await.unwrap()
+            } else {
+                let mut client = Client::from_torrent_file(torrent);
+                client.torrent.dht = dht;
+                client
+            };
 
-            client.torrent.download(target_filename).await;
+            if let Some(max_requests) = max_requests {
+                client.torrent.max_requests = max_requests;
+            }
+            client.torrent.sequential = sequential;
+            client.torrent.mmap = mmap;
+            // Ctrl-C ends up here too, once the resume file is saved
+            if let Err(e) = client.torrent.download(target_filename.clone()).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            client.state = TorrentState::Complete;
+            if seed {
+                client.state = TorrentState::Seeding;
+                client.torrent.seed(target_filename).await.unwrap();
+            }
+        }
+        Command::Seed {
+            target_filename,
+            torrent,
+            dht,
+        } => {
+            let mut client = Client::from_torrent_file(torrent);
+            client.torrent.dht = dht.start().await;
+            client.state = TorrentState::Seeding;
+            client.torrent.seed(target_filename).await.unwrap();
         }
     }
 }
@@ -0,0 +1,195 @@
+use crate::scheduler::BlockRequest;
+use thiserror::Error;
+
+// message ids on the wire, BEP 3, BEP 6 and BEP 10
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const SUGGEST_PIECE: u8 = 13;
+const HAVE_ALL: u8 = 14;
+const HAVE_NONE: u8 = 15;
+const REJECT_REQUEST: u8 = 16;
+const ALLOWED_FAST: u8 = 17;
+const EXTENDED: u8 = 20;
+
+// a block plus its header fits many times over, so does the bitfield of
+// any torrent anybody would share
+pub const MAX_MESSAGE_LENGTH: u32 = 1 << 21;
+
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+pub enum MessageError {
+    #[error("message {id} with a payload of {length} bytes")]
+    BadLength { id: u8, length: usize },
+    #[error("message of {0} bytes is too long")]
+    TooLong(u32),
+}
+
+/// One peer wire message.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    // one bit per piece, high bit first
+    Bitfield(Vec<u8>),
+    Request(BlockRequest),
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel(BlockRequest),
+    // the peer's DHT port
+    Port(u16),
+    // the fast extension: a piece worth asking for, e.g. because it's in cache
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    // a request that won't be answered
+    RejectRequest(BlockRequest),
+    // a piece that may be requested even while choked
+    AllowedFast(u32),
+    // `id` is the extension's id, 0 is the extended handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    // ids we don't speak, handed on as they are
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+impl Message {
+    /// Decodes a frame without its length prefix. Empty frames are keep-alives.
+    pub fn decode(frame: &[u8]) -> Result<Self, MessageError> {
+        let Some((&id, payload)) = frame.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        let int = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+        let block_request = || BlockRequest {
+            piece: int(0),
+            begin: int(4),
+            length: int(8),
+        };
+        Ok(match (id, payload.len()) {
+            (CHOKE, 0) => Message::Choke,
+            (UNCHOKE, 0) => Message::Unchoke,
+            (INTERESTED, 0) => Message::Interested,
+            (NOT_INTERESTED, 0) => Message::NotInterested,
+            (HAVE, 4) => Message::Have(int(0)),
+            (BITFIELD, _) => Message::Bitfield(payload.to_vec()),
+            (REQUEST, 12) => Message::Request(block_request()),
+            (PIECE, 8..) => Message::Piece {
+                index: int(0),
+                begin: int(4),
+                block: payload[8..].to_vec(),
+            },
+            (CANCEL, 12) => Message::Cancel(block_request()),
+            (PORT, 2) => Message::Port(u16::from_be_bytes([payload[0], payload[1]])),
+            (SUGGEST_PIECE, 4) => Message::SuggestPiece(int(0)),
+            (HAVE_ALL, 0) => Message::HaveAll,
+            (HAVE_NONE, 0) => Message::HaveNone,
+            (REJECT_REQUEST, 12) => Message::RejectRequest(block_request()),
+            (ALLOWED_FAST, 4) => Message::AllowedFast(int(0)),
+            (EXTENDED, 1..) => Message::Extended {
+                id: payload[0],
+                payload: payload[1..].to_vec(),
+            },
+            (CHOKE..=PORT | SUGGEST_PIECE..=ALLOWED_FAST | EXTENDED, length) => {
+                return Err(MessageError::BadLength { id, length })
+            }
+            _ => Message::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        })
+    }
+
+    /// True for the messages of the fast extension (BEP 6), which only a
+    /// peer that set its bit in the handshake may send.
+    pub fn needs_fast_extension(&self) -> bool {
+        matches!(
+            self,
+            Message::SuggestPiece(_)
+                | Message::HaveAll
+                | Message::HaveNone
+                | Message::RejectRequest(_)
+                | Message::AllowedFast(_)
+        )
+    }
+
+    /// The whole frame, length prefix and all.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = vec![0; 4];
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke => frame.push(CHOKE),
+            Message::Unchoke => frame.push(UNCHOKE),
+            Message::Interested => frame.push(INTERESTED),
+            Message::NotInterested => frame.push(NOT_INTERESTED),
+            Message::HaveAll => frame.push(HAVE_ALL),
+            Message::HaveNone => frame.push(HAVE_NONE),
+            Message::Have(index) | Message::SuggestPiece(index) | Message::AllowedFast(index) => {
+                frame.push(match self {
+                    Message::Have(_) => HAVE,
+                    Message::SuggestPiece(_) => SUGGEST_PIECE,
+                    _ => ALLOWED_FAST,
+                });
+                frame.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::Bitfield(bits) => {
+                frame.push(BITFIELD);
+                frame.extend_from_slice(bits);
+            }
+            Message::Request(request)
+            | Message::Cancel(request)
+            | Message::RejectRequest(request) => {
+                frame.push(match self {
+                    Message::Request(_) => REQUEST,
+                    Message::Cancel(_) => CANCEL,
+                    _ => REJECT_REQUEST,
+                });
+                frame.extend_from_slice(&request.piece.to_be_bytes());
+                frame.extend_from_slice(&request.begin.to_be_bytes());
+                frame.extend_from_slice(&request.length.to_be_bytes());
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                frame.push(PIECE);
+                frame.extend_from_slice(&index.to_be_bytes());
+                frame.extend_from_slice(&begin.to_be_bytes());
+                frame.extend_from_slice(block);
+            }
+            Message::Port(port) => {
+                frame.push(PORT);
+                frame.extend_from_slice(&port.to_be_bytes());
+            }
+            Message::Extended { id, payload } => {
+                frame.push(EXTENDED);
+                frame.push(*id);
+                frame.extend_from_slice(payload);
+            }
+            Message::Unknown { id, payload } => {
+                frame.push(*id);
+                frame.extend_from_slice(payload);
+            }
+        }
+        let length = (frame.len() - 4) as u32;
+        frame[..4].copy_from_slice(&length.to_be_bytes());
+        frame
+    }
+}
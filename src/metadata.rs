@@ -0,0 +1,197 @@
+use crate::bencode::{self, BencodeValue};
+use crate::extension::{ExtendedHandshake, ExtensionHandler};
+use crate::message::Message;
+use crate::peer::Peer;
+use sha1::{Digest, Sha1};
+use std::sync::{Arc, Mutex};
+
+// the info dict is sent in 16 KiB pieces
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+// refuse to buffer anything bigger than this, real info dicts are far smaller
+const MAX_METADATA_SIZE: usize = 16 << 20;
+
+// ut_metadata msg_type values
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+/// Progress of fetching the info dict from one peer.
+#[derive(Debug, Default)]
+pub struct MetadataDownload {
+    data: Vec<u8>,
+    received: Vec<bool>,
+    error: Option<String>,
+}
+impl MetadataDownload {
+    fn n_pieces(&self) -> usize {
+        self.received.len()
+    }
+
+    fn is_complete(&self) -> bool {
+        !self.received.is_empty() && !self.received.contains(&false)
+    }
+}
+
+/// The ut_metadata extension (BEP 9). It hands out our info dict if we have
+/// one and collects the pieces of the peer's otherwise.
+pub struct UtMetadata {
+    metadata: Option<Arc<Vec<u8>>>,
+    download: Arc<Mutex<MetadataDownload>>,
+}
+impl UtMetadata {
+    #[allow(dead_code)]
+    pub fn serving(metadata: Arc<Vec<u8>>) -> Self {
+        Self {
+            metadata: Some(metadata),
+            download: Arc::default(),
+        }
+    }
+
+    pub fn fetching(download: Arc<Mutex<MetadataDownload>>) -> Self {
+        Self {
+            metadata: None,
+            download,
+        }
+    }
+
+    fn message(msg_type: i64, piece: usize) -> BencodeValue {
+        BencodeValue::dict([
+            ("msg_type", BencodeValue::from(msg_type)),
+            ("piece", BencodeValue::from(piece as i64)),
+        ])
+    }
+}
+impl ExtensionHandler for UtMetadata {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        if let Some(metadata) = &self.metadata {
+            handshake.metadata_size = Some(metadata.len() as u64);
+        }
+    }
+
+    fn on_handshake(&mut self, remote: &ExtendedHandshake) {
+        if self.metadata.is_some() {
+            return;
+        }
+        let mut download = self.download.lock().unwrap();
+        if !download.received.is_empty() {
+            return;
+        }
+        match remote.metadata_size {
+            Some(size) if size > 0 && size as usize <= MAX_METADATA_SIZE => {
+                download.data = vec![0; size as usize];
+                download.received = vec![false; (size as usize).div_ceil(METADATA_PIECE_SIZE)];
+            }
+            Some(size) => download.error = Some(format!("invalid metadata_size {}", size)),
+            None => {}
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        // data messages have the piece appended right after the dict
+        let (msg, dict_len) = bencode::decode_bencoded_value(payload)?;
+        let piece = msg
+            .get("piece")
+            .and_then(BencodeValue::as_int)
+            .and_then(|p| usize::try_from(p).ok())
+            .ok_or_else(|| anyhow::anyhow!("ut_metadata message without piece"))?;
+
+        match msg.get("msg_type").and_then(BencodeValue::as_int) {
+            Some(REQUEST) => {
+                let chunk = self
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.chunks(METADATA_PIECE_SIZE).nth(piece));
+                let reply = match chunk {
+                    Some(chunk) => {
+                        let mut reply = BencodeValue::dict([
+                            ("msg_type", BencodeValue::from(DATA)),
+                            ("piece", BencodeValue::from(piece as i64)),
+                            (
+                                "total_size",
+                                BencodeValue::from(self.metadata.as_ref().unwrap().len() as i64),
+                            ),
+                        ])
+                        .encode();
+                        reply.extend_from_slice(chunk);
+                        reply
+                    }
+                    None => Self::message(REJECT, piece).encode(),
+                };
+                Ok(vec![reply])
+            }
+            Some(DATA) => {
+                let mut download = self.download.lock().unwrap();
+                if piece >= download.n_pieces() {
+                    anyhow::bail!("peer sent unknown metadata piece {}", piece);
+                }
+                let start = piece * METADATA_PIECE_SIZE;
+                let expected_len = METADATA_PIECE_SIZE.min(download.data.len() - start);
+                let data = &payload[dict_len..];
+                if data.len() != expected_len {
+                    anyhow::bail!("metadata piece {} has the wrong length", piece);
+                }
+                download.data[start..start + expected_len].copy_from_slice(data);
+                download.received[piece] = true;
+                Ok(Vec::new())
+            }
+            Some(REJECT) => {
+                self.download.lock().unwrap().error =
+                    Some(format!("peer rejected metadata piece {}", piece));
+                Ok(Vec::new())
+            }
+            // unknown message types are ignored
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Fetches the info dict of a magnet link from a peer that has already done a
+/// handshake with the extension bit set, using ut_metadata (BEP 9).
+/// The bytes are checked against `info_hash` before they are returned.
+pub async fn fetch_metadata(peer: &mut Peer, info_hash: &[u8; 20]) -> anyhow::Result<Vec<u8>> {
+    let download = Arc::new(Mutex::new(MetadataDownload::default()));
+    peer.register_extension(UtMetadata::fetching(download.clone()));
+    peer.send_extended_handshake().await?;
+
+    let mut requested = false;
+    loop {
+        // nothing but the info dict matters here
+        if let Message::Extended { id, payload } = peer.read_message().await? {
+            peer.handle_extended(id, &payload).await?;
+        }
+        if peer.remote_extensions.is_none() {
+            continue;
+        }
+
+        let n_pieces = {
+            let mut download = download.lock().unwrap();
+            if let Some(error) = download.error.take() {
+                anyhow::bail!(error);
+            }
+            if download.is_complete() {
+                let metadata = std::mem::take(&mut download.data);
+                let hash: [u8; 20] = Sha1::digest(&metadata).into();
+                if hash != *info_hash {
+                    anyhow::bail!("metadata does not match the info hash");
+                }
+                return Ok(metadata);
+            }
+            download.n_pieces()
+        };
+
+        if !requested {
+            if n_pieces == 0 {
+                anyhow::bail!("peer did not send metadata_size");
+            }
+            for piece in 0..n_pieces {
+                let request = UtMetadata::message(REQUEST, piece).encode();
+                peer.send_extension_message("ut_metadata", &request).await?;
+            }
+            requested = true;
+        }
+    }
+}
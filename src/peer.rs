@@ -1,264 +1,341 @@
-use crate::torrent::TorrentFile;
+use crate::bitfield::Bitfield;
+use crate::extension::{ExtendedHandshake, ExtensionHandler, ExtensionRegistry};
+use crate::message::{Message, MessageError, MAX_MESSAGE_LENGTH};
 use anyhow::{Ok, Result};
-use serde::Serialize;
-use std::mem::transmute;
+use sha1::{Digest, Sha1};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, Interest},
     net::TcpStream,
 };
 
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// reserved[5] & 0x10 means the extension protocol (BEP 10)
+const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+// reserved[7] & 0x04 means the fast extension (BEP 6)
+const FAST_BIT: (usize, u8) = (7, 0x04);
+// how many pieces we let a peer request while it's choked
+pub const ALLOWED_FAST_COUNT: usize = 10;
+// sent as `v` in the extended handshake
+const CLIENT_VERSION: &str = concat!("jab ", env!("CARGO_PKG_VERSION"));
+// the port we tell peers and trackers we listen on
+pub const LISTEN_PORT: u16 = 6881;
+// who we say we are, to peers and trackers alike
+pub const PEER_ID: [u8; 20] = *b"00112233445566778899";
+// how many outstanding requests we accept from a peer, sent as `reqq`
+pub const MAX_REQUEST_QUEUE: u32 = 250;
+
 #[repr(C)]
 pub struct Handshake {
     length: u8,
     bittorrent: [u8; 19],
     reserved: [u8; 8],
-    info_hash: [u8; 20],
+    pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
+impl Handshake {
+    fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_BIT.0] |= EXTENSION_BIT.1;
+        reserved[FAST_BIT.0] |= FAST_BIT.1;
+        Self {
+            length: 19,
+            bittorrent: b"BitTorrent protocol".to_owned(),
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /*** pretty much all of this fancy memory work is from *
+     * Jon Gjengset's stream of the same challenge        **/
+    fn as_bytes_mut(&mut self) -> &mut [u8; std::mem::size_of::<Handshake>()] {
+        let bytes = self as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
+        unsafe { &mut *bytes }
+    }
+
+    fn check_protocol(&self) -> Result<()> {
+        if self.length != 19 || self.bittorrent != *b"BitTorrent protocol" {
+            anyhow::bail!("peer does not speak the BitTorrent protocol");
+        }
+        Ok(())
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BIT.0] & EXTENSION_BIT.1 != 0
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[FAST_BIT.0] & FAST_BIT.1 != 0
+    }
+}
+
+/// The pieces a peer at `ip` may request while choked, `k` of them or all the
+/// torrent has if that's fewer. Both sides can work it out, it's the same
+/// for every peer behind a /24.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], n_pieces: u32, k: usize) -> Vec<u32> {
+    let k = k.min(n_pieces as usize);
+    let mut allowed = Vec::with_capacity(k);
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while allowed.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for y in x.chunks(4) {
+            let index = u32::from_be_bytes(y.try_into().unwrap()) % n_pieces;
+            if allowed.len() < k && !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+    allowed
+}
 
 pub struct Peer {
     connection: TcpStream,
+    // number of pieces from this peer that failed their hash check
+    pub hash_failures: u32,
+    // whether the peer set the extension bit in its handshake
+    pub supports_extensions: bool,
+    // whether we both speak the fast extension, which we always do
+    pub supports_fast: bool,
+    // the peer's extended handshake, once it has sent one
+    pub remote_extensions: Option<ExtendedHandshake>,
+    extensions: ExtensionRegistry,
+    // bytes of a message that hasn't fully arrived yet
+    read_buf: Vec<u8>,
+    // both sides start out choking and not interested
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
 }
 impl Peer {
     pub async fn new(peer_string: String) -> Self {
-        let connection = TcpStream::connect(peer_string).await.unwrap();
-        Self { connection }
+        Self::connect(peer_string).await.unwrap()
+    }
+
+    pub async fn connect(peer_string: String) -> Result<Self> {
+        let connection =
+            tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(peer_string)).await??;
+        Ok(Self::accept(connection))
     }
 
-    pub async fn is_ready(self: &Self) -> bool {
+    /// Wraps a connection a peer opened to us. Answer it with `accept_handshake`.
+    pub fn accept(connection: TcpStream) -> Self {
+        Self {
+            connection,
+            hash_failures: 0,
+            supports_extensions: false,
+            supports_fast: false,
+            remote_extensions: None,
+            extensions: ExtensionRegistry::default(),
+            read_buf: Vec::new(),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub async fn is_ready(&self) -> bool {
         loop {
             let ready = self.connection.ready(Interest::READABLE).await;
-            match ready {
-                Result::Ok(_ready) => {
-                    return true;
-                }
-                _ => {}
+            if let Result::Ok(_ready) = ready {
+                return true;
             }
         }
     }
 
-    pub async fn handshake(&mut self, torrent: &TorrentFile, peer_id: [u8; 20]) -> Handshake {
-        let info_hash = torrent.info.hash();
-        // let mut connection = TcpStream::connect(peer_string).await.unwrap();
+    pub async fn handshake(&mut self, info_hash: [u8; 20], peer_id: [u8; 20]) -> Result<Handshake> {
+        let mut handshake = Handshake::new(info_hash, peer_id);
+        self.connection.write_all(handshake.as_bytes_mut()).await?;
+        // a peer that takes the connection but never answers is no peer
+        tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            self.connection.read_exact(handshake.as_bytes_mut()),
+        )
+        .await??;
+        handshake.check_protocol()?;
+        if handshake.info_hash != info_hash {
+            anyhow::bail!("peer sent a handshake for a different torrent");
+        }
+        self.supports_extensions = handshake.supports_extensions();
+        self.supports_fast = handshake.supports_fast();
 
-        let mut handshake = Handshake {
-            length: 19,
-            bittorrent: b"BitTorrent protocol".to_owned(),
-            reserved: [0; 8],
-            info_hash,
-            peer_id,
-        };
+        Ok(handshake)
+    }
 
-        /*** pretty much all of this fancy memory work is from *
-         * Jon Gjengset's stream of the same challenge        **/
-        let handshake_bytes =
-            &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
-        let handshake_bytes: &mut [u8; std::mem::size_of::<Handshake>()] =
-            unsafe { &mut *handshake_bytes };
-        self.connection.write_all(handshake_bytes).await.unwrap();
-        self.connection.read_exact(handshake_bytes).await.unwrap();
-        assert_eq!(handshake.bittorrent, *b"BitTorrent protocol");
-        assert_eq!(handshake.reserved.len(), 8);
-        assert_eq!(handshake.info_hash, info_hash);
+    /// The other side of `handshake`: waits for the peer's handshake and answers
+    /// it, if `known` says we have the torrent it asks for.
+    pub async fn accept_handshake(
+        &mut self,
+        known: impl Fn(&[u8; 20]) -> bool,
+        peer_id: [u8; 20],
+    ) -> Result<Handshake> {
+        let mut handshake = Handshake::new([0; 20], peer_id);
+        tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            self.connection.read_exact(handshake.as_bytes_mut()),
+        )
+        .await??;
+        handshake.check_protocol()?;
+        if !known(&handshake.info_hash) {
+            anyhow::bail!("peer asked for a torrent we don't have");
+        }
+        self.supports_extensions = handshake.supports_extensions();
+        self.supports_fast = handshake.supports_fast();
 
-        handshake
+        let mut reply = Handshake::new(handshake.info_hash, peer_id);
+        self.connection.write_all(reply.as_bytes_mut()).await?;
+        Ok(handshake)
     }
 
-    pub async fn wait_for_msg(&mut self, id: MessageId) -> anyhow::Result<Message> {
+    /// Reads the next message, whatever it is. Partial messages are kept
+    /// between calls, so this can be raced against other futures in a
+    /// `select!` without losing data. Choke and interest messages update
+    /// `peer_choking` and `peer_interested` on the way.
+    pub async fn read_message(&mut self) -> Result<Message> {
         loop {
-            // std::thread::sleep(Duration::from_millis(1000));
-            let mut msg_length: [u8; 4] = [0; 4];
-            self.connection
-                .read_exact(&mut msg_length)
-                .await
-                .expect("some length, really");
-
-            // println!("waiting, read {:#?}", msg_length.to_vec());
-
-            let l = u32::from_be_bytes(msg_length);
-            // let l = 0;
-            if l == 0 {
-                println!("hb");
-                return Ok(Message {
-                    length: 0,
-                    message_id: MessageId::Heartbeat,
-                    payload: None,
-                });
-            }
-
-            let mut msg: Message = Message::heartbeat();
-            // let mut payload: Option<Vec<u8>>;
-            let mut message_id: [u8; 1] = [0];
-            self.connection
-                .read_exact(&mut message_id)
-                .await
-                .expect("expected a message");
-            if MessageId::from(message_id[0]) == id {
-                println!("got {:?} as expected", id);
-                msg = Message {
-                    length: l,
-                    message_id: id,
-                    payload: None,
-                };
-            } else {
-                println!("expected {:?} message!", id);
-                println!("got: {:#?}", message_id);
-            }
-
-            if l == 1 {
-                println!("no payload");
-                // let mut buf: [u8; 1] = [0];
-                // self.connection.read_exact(&mut buf).await.unwrap();
-                // println!("\"empty\" payload: {:#?}", buf);
-                msg.payload = None;
-            } else {
-                let mut buf: Vec<u8> = Vec::with_capacity((l - 1) as usize);
-                unsafe {
-                    buf.set_len(l as usize - 1);
+            if self.read_buf.len() >= 4 {
+                let length = u32::from_be_bytes(self.read_buf[..4].try_into().unwrap());
+                if length > MAX_MESSAGE_LENGTH {
+                    return Err(MessageError::TooLong(length).into());
+                }
+                if self.read_buf.len() >= 4 + length as usize {
+                    let frame: Vec<u8> = self.read_buf.drain(..4 + length as usize).collect();
+                    let msg = Message::decode(&frame[4..])?;
+                    match msg {
+                        Message::Choke => self.peer_choking = true,
+                        Message::Unchoke => self.peer_choking = false,
+                        Message::Interested => self.peer_interested = true,
+                        Message::NotInterested => self.peer_interested = false,
+                        _ => {}
+                    }
+                    return Ok(msg);
                 }
-                self.connection.read_exact(&mut buf).await.unwrap();
-                msg.payload = Some(buf);
             }
-
-            return Ok(msg);
+            if self.connection.read_buf(&mut self.read_buf).await? == 0 {
+                anyhow::bail!("connection closed");
+            }
         }
     }
 
-    pub async fn send(&mut self, buf: Vec<u8>) -> Result<()> {
-        self.connection.write_all(&buf.as_slice()).await.unwrap();
-        Ok(())
+    pub fn addr(&self) -> Result<SocketAddr> {
+        Ok(self.connection.peer_addr()?)
     }
-}
 
-#[allow(dead_code)]
-#[derive(Copy, Clone, Debug, PartialEq)]
-#[repr(u8)]
-pub enum MessageId {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
-    Heartbeat = 9,
-}
-impl From<u8> for MessageId {
-    fn from(value: u8) -> Self {
-        // if value < 9 {
-        unsafe { transmute(value) }
-        // } else {
-        //     panic!("invalid message id")
-        // }
+    /// Tells the peer which pieces we have, first thing after the handshake.
+    /// With the fast extension there's always something to say.
+    pub async fn send_bitfield(&mut self, have: &Bitfield) -> Result<()> {
+        let msg = match (self.supports_fast, have.count()) {
+            (false, 0) => return Ok(()),
+            (true, 0) => Message::HaveNone,
+            (true, _) if have.is_full() => Message::HaveAll,
+            _ => Message::Bitfield(have.as_bytes().to_vec()),
+        };
+        self.send(&msg).await
     }
-}
-#[repr(C)]
-#[derive(Clone, Debug)]
-pub struct Message {
-    pub length: u32,
-    pub message_id: MessageId,
-    pub payload: Option<Vec<u8>>,
-}
-impl Message {
-    pub fn from_bytes(buf: Vec<u8>) -> Self {
-        let (left, right) = buf.split_at(4);
-        let length = u32::from_be_bytes(left.try_into().unwrap());
-        let (message_id, payload_bytes): (&u8, &[u8]) = right.split_first().unwrap();
-        let message_id = unsafe { transmute(*message_id) };
-        Message {
-            length,
-            message_id,
-            payload: match payload_bytes.len() > 0 {
-                true => Some(payload_bytes.to_vec()),
-                false => None,
-            },
+
+    /// Chokes or unchokes the peer. Nothing is sent if that's how it is already.
+    pub async fn set_choking(&mut self, choking: bool) -> Result<()> {
+        if self.am_choking == choking {
+            return Ok(());
         }
+        let msg = match choking {
+            true => Message::Choke,
+            false => Message::Unchoke,
+        };
+        self.send(&msg).await?;
+        self.am_choking = choking;
+        Ok(())
     }
 
-    pub fn new_request_message(payload: RequestPayload) -> Message {
-        let payload_vec: Vec<u8> = payload.into();
-        let payload_bytes: &[u8] = payload_vec.as_slice();
-        let length = std::mem::size_of::<MessageId>() + payload_bytes.len();
-        // std::mem::size_of::<RequestPayload>();
-        // println!("length is {}", length as u32);
-        Message {
-            length: length as u32,
-            message_id: MessageId::Request,
-            payload: Some(payload_bytes.to_vec()),
+    /// Tells the peer whether we want anything from it, if that changed.
+    pub async fn set_interested(&mut self, interested: bool) -> Result<()> {
+        if self.am_interested == interested {
+            return Ok(());
         }
+        let msg = match interested {
+            true => Message::Interested,
+            false => Message::NotInterested,
+        };
+        self.send(&msg).await?;
+        self.am_interested = interested;
+        Ok(())
     }
 
-    pub fn heartbeat() -> Message {
-        Message {
-            length: 0,
-            message_id: MessageId::Heartbeat,
-            payload: None,
-        }
+    /// Plugs in an extension. Register everything before `send_extended_handshake`,
+    /// the handshake tells the peer which extensions we speak.
+    pub fn register_extension(&mut self, handler: impl ExtensionHandler + 'static) -> u8 {
+        self.extensions.register(Box::new(handler))
     }
-}
-impl Into<Vec<u8>> for Message {
-    fn into(self) -> Vec<u8> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.length.to_be_bytes());
-        v.push(self.message_id as u8);
-        if let Some(payload) = self.payload {
-            v.extend_from_slice(&payload);
+
+    pub async fn send_extended_handshake(&mut self) -> Result<()> {
+        if !self.supports_extensions {
+            anyhow::bail!("peer does not support the extension protocol");
         }
-        // println!("converting {:?} message into: {:#?}", self.message_id, v);
-        v
+        let mut handshake = self.extensions.handshake();
+        handshake.v = Some(CLIENT_VERSION.to_owned());
+        handshake.p = Some(LISTEN_PORT);
+        handshake.reqq = Some(MAX_REQUEST_QUEUE);
+        handshake.yourip = self.connection.peer_addr().ok().map(|addr| addr.ip());
+        self.send_extended(0, &handshake.encode()).await
     }
-}
 
-#[repr(C)]
-#[derive(Debug, Serialize)]
-pub struct RequestPayload {
-    pub index: u32, // the zero-based piece index
-    pub begin: u32, // the zero-based byte offset within the piece
-    pub length: [u8; 4],
-}
-impl Into<Vec<u8>> for RequestPayload {
-    fn into(self) -> Vec<u8> {
-        let mut v = Vec::new();
-        v.extend_from_slice(&self.index.to_be_bytes());
-        v.extend_from_slice(&self.begin.to_be_bytes());
-        v.extend_from_slice(&self.length);
-        v
+    /// Sends a message for the extension called `name`, using the id the peer
+    /// asked for in its extended handshake.
+    pub async fn send_extension_message(&mut self, name: &str, payload: &[u8]) -> Result<()> {
+        let id = self
+            .remote_extensions
+            .as_ref()
+            .and_then(|remote| remote.m.get(name))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("peer does not support {}", name))?;
+        self.send_extended(id, payload).await
     }
-}
-impl RequestPayload {
-    fn as_bytes(self: Self) -> [u8; 12] {
-        let mut b: [u8; 12] = [0; 12];
-        let payload_vec: Vec<u8> = self.into();
-        for (i, v) in payload_vec.iter().enumerate() {
-            b[i] = *v;
+
+    /// Handles an `Extended` message: either the peer's extended handshake,
+    /// or a message for one of the registered extensions.
+    pub async fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        if id == 0 {
+            let handshake = ExtendedHandshake::from_bytes(payload)?;
+            let remote = match self.remote_extensions.take() {
+                Some(mut remote) => {
+                    remote.update(handshake);
+                    remote
+                }
+                None => handshake,
+            };
+            self.extensions.on_handshake(&remote);
+            self.remote_extensions = Some(remote);
+            return Ok(());
         }
-        b
-    }
-}
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct PiecePayload {
-    pub index: u32, // the zero-based piece index
-    pub begin: u32, // the zero-based byte offset within the piece
-    pub block_bytes: Vec<u8>,
-}
-impl PiecePayload {
-    pub fn from_bytes(buf: Vec<u8>) -> Self {
-        // println!("decoding piecepayload bytes {:#?}", buf);
+        // ids we never handed out are ignored
+        let Some(handler) = self.extensions.get_mut(id) else {
+            return Ok(());
+        };
+        let name = handler.name();
+        for reply in handler.on_message(payload)? {
+            self.send_extension_message(name, &reply).await?;
+        }
+        Ok(())
+    }
 
-        let (index, rest) = buf.split_at(4);
-        let (begin, block) = rest.split_at(4);
-        // println!("index: {}", u32::from_be_bytes(index.try_into().unwrap()));
-        // println!("begin: {}", u32::from_be_bytes(begin.try_into().unwrap()));
-        // println!("block: {:#?}", &block.len());
+    /// Sends an extension protocol message. `id` is the id the peer gave the
+    /// extension in its extended handshake, 0 is the handshake itself.
+    pub async fn send_extended(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        let msg = Message::Extended {
+            id,
+            payload: payload.to_vec(),
+        };
+        self.send(&msg).await
+    }
 
-        Self {
-            index: u32::from_be_bytes([index[0], index[1], index[2], index[3]]),
-            begin: u32::from_be_bytes([begin[0], begin[1], begin[2], begin[3]]),
-            block_bytes: block.to_vec(),
-        }
+    pub async fn send(&mut self, msg: &Message) -> Result<()> {
+        self.connection.write_all(&msg.encode()).await?;
+        Ok(())
     }
 }
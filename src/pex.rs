@@ -0,0 +1,192 @@
+use crate::bencode::{self, BencodeValue};
+use crate::extension::ExtensionHandler;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// BEP 11 asks for no more than one message a minute per peer
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// and no more than this many added and dropped peers in one
+const MAX_PEX_PEERS: usize = 50;
+
+// flags that go with an added peer, the others are about encryption, uTP
+// and holepunching which we don't do
+pub const FLAG_SEED: u8 = 0x02;
+// we got through to the peer, so others can too
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// One ut_pex message: peers that joined and left since the last one.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (added4, added6): (Vec<&(SocketAddr, u8)>, Vec<_>) =
+            self.added.iter().partition(|(addr, _)| addr.is_ipv4());
+        let (dropped4, dropped6): (Vec<_>, Vec<_>) =
+            self.dropped.iter().partition(|addr| addr.is_ipv4());
+        let flags = |added: &[&(SocketAddr, u8)]| -> Vec<u8> {
+            added.iter().map(|&&(_, flags)| flags).collect()
+        };
+        BencodeValue::dict([
+            ("added", compact(added4.iter().map(|(addr, _)| addr))),
+            ("added.f", BencodeValue::from(flags(&added4))),
+            ("added6", compact(added6.iter().map(|(addr, _)| addr))),
+            ("added6.f", BencodeValue::from(flags(&added6))),
+            ("dropped", compact(dropped4)),
+            ("dropped6", compact(dropped6)),
+        ])
+        .encode()
+    }
+
+    /// Parses a message. Missing keys are just empty, and peers without
+    /// flags get none.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let dict = bencode::decode(bytes)?;
+        if !matches!(dict, BencodeValue::Map(_)) {
+            anyhow::bail!("ut_pex message is not a dict");
+        }
+        let bytes = |key| {
+            dict.get(key)
+                .and_then(BencodeValue::as_bytes)
+                .unwrap_or(&[])
+        };
+        let with_flags = |addrs: Vec<SocketAddr>, flags: &[u8]| {
+            addrs
+                .into_iter()
+                .enumerate()
+                .map(|(i, addr)| (addr, flags.get(i).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+        let mut added = with_flags(parse_compact(bytes("added"), 4)?, bytes("added.f"));
+        added.extend(with_flags(
+            parse_compact(bytes("added6"), 16)?,
+            bytes("added6.f"),
+        ));
+        let mut dropped = parse_compact(bytes("dropped"), 4)?;
+        dropped.extend(parse_compact(bytes("dropped6"), 16)?);
+        Ok(Self { added, dropped })
+    }
+}
+
+/// Addresses packed the compact way: the ip, then the port, both big endian.
+fn compact<'a>(addrs: impl IntoIterator<Item = &'a SocketAddr>) -> BencodeValue {
+    let mut out = Vec::new();
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => out.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => out.extend_from_slice(&ip.octets()),
+        }
+        out.extend_from_slice(&addr.port().to_be_bytes());
+    }
+    BencodeValue::from(out)
+}
+
+fn parse_compact(bytes: &[u8], ip_len: usize) -> anyhow::Result<Vec<SocketAddr>> {
+    if !bytes.len().is_multiple_of(ip_len + 2) {
+        anyhow::bail!("compact peers of {} bytes", bytes.len());
+    }
+    Ok(bytes
+        .chunks(ip_len + 2)
+        .map(|chunk| {
+            let ip = match ip_len {
+                4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&chunk[..4]).unwrap())),
+                _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&chunk[..16]).unwrap())),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([chunk[ip_len], chunk[ip_len + 1]]))
+        })
+        .collect())
+}
+
+/// What we told one peer about the swarm so far. Works out the next message
+/// for it, at most one every PEX_INTERVAL.
+#[derive(Debug, Default)]
+pub struct PexState {
+    told: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+impl PexState {
+    /// The difference between `connected` and what the peer heard last time,
+    /// or None if it's too soon or nothing changed. Peers past MAX_PEX_PEERS
+    /// are left for the next message.
+    pub fn update(&mut self, connected: &[(SocketAddr, u8)], now: Instant) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last| now.saturating_duration_since(last) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(addr, _)| !self.told.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .told
+            .iter()
+            .filter(|addr| !connected.iter().any(|(other, _)| other == *addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let msg = PexMessage { added, dropped };
+        if msg.is_empty() {
+            return None;
+        }
+        for (addr, _) in &msg.added {
+            self.told.insert(*addr);
+        }
+        for addr in &msg.dropped {
+            self.told.remove(addr);
+        }
+        self.last_sent = Some(now);
+        Some(msg)
+    }
+}
+
+/// The ut_pex extension (BEP 11). Peers it hears about go to `discovered`,
+/// sending is up to whoever owns the connection, see `PexState`.
+pub struct UtPex {
+    discovered: mpsc::Sender<SocketAddr>,
+    last_received: Option<Instant>,
+}
+impl UtPex {
+    pub fn new(discovered: mpsc::Sender<SocketAddr>) -> Self {
+        Self {
+            discovered,
+            last_received: None,
+        }
+    }
+}
+impl ExtensionHandler for UtPex {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        // a peer that sends more often than it should gets ignored in
+        // between, allowing for some jitter in its timer
+        let now = Instant::now();
+        if self
+            .last_received
+            .is_some_and(|last| now.saturating_duration_since(last) < PEX_INTERVAL / 2)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_received = Some(now);
+
+        let msg = PexMessage::from_bytes(payload)?;
+        for (addr, _) in msg.added.into_iter().take(MAX_PEX_PEERS) {
+            // if the pool is backed up it has enough peers to try anyway
+            let _ = self.discovered.try_send(addr);
+        }
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,182 @@
+use crate::bencode::{self, BencodeValue};
+use crate::bitfield::Bitfield;
+use crate::torrent::FileEntry;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// What we know about a download, saved next to it so the next start can skip
+/// hashing everything again.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    // pieces that are verified and on disk
+    pub have: Vec<bool>,
+    // size and mtime of every file, in layout order
+    pub files: Vec<FileStamp>,
+    // blocks of unfinished pieces that are on disk, but not checked yet
+    pub partial: Vec<(u32, Vec<bool>)>,
+}
+
+/// Enough about a file to notice when something else touched it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FileStamp {
+    pub length: u64,
+    // nanoseconds since the epoch
+    pub mtime: i64,
+}
+impl FileStamp {
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            length: metadata.len(),
+            mtime: mtime.as_nanos() as i64,
+        })
+    }
+}
+
+/// Where the resume file for a download to `target` goes.
+pub fn resume_path(target: &str) -> PathBuf {
+    PathBuf::from(format!("{}.resume", target))
+}
+
+impl ResumeData {
+    /// Stamps the files of `layout` as they are right now.
+    pub fn new(
+        info_hash: [u8; 20],
+        have: Vec<bool>,
+        layout: &[FileEntry],
+        partial: Vec<(u32, Vec<bool>)>,
+    ) -> Self {
+        let files = layout
+            .iter()
+            .map(|entry| {
+                FileStamp::of(&entry.path).unwrap_or(FileStamp {
+                    length: 0,
+                    mtime: 0,
+                })
+            })
+            .collect();
+        Self {
+            info_hash,
+            have,
+            files,
+            partial,
+        }
+    }
+
+    /// True if this is about the same torrent, and none of its files changed
+    /// since it was saved.
+    pub fn matches(&self, info_hash: &[u8; 20], n_pieces: u32, layout: &[FileEntry]) -> bool {
+        self.info_hash == *info_hash
+            && self.have.len() == n_pieces as usize
+            && self.files.len() == layout.len()
+            && self
+                .files
+                .iter()
+                .zip(layout)
+                .all(|(stamp, entry)| FileStamp::of(&entry.path) == Some(*stamp))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let files = self
+            .files
+            .iter()
+            .map(|file| {
+                BencodeValue::dict([
+                    ("length", BencodeValue::from(file.length as i64)),
+                    ("mtime", BencodeValue::from(file.mtime)),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let partial = self
+            .partial
+            .iter()
+            .map(|(piece, blocks)| {
+                BencodeValue::dict([
+                    ("piece", BencodeValue::from(*piece as i64)),
+                    ("blocks", BencodeValue::from(pack_bits(blocks))),
+                ])
+            })
+            .collect::<Vec<_>>();
+        BencodeValue::dict([
+            ("info-hash", BencodeValue::from(&self.info_hash[..])),
+            ("pieces", BencodeValue::from(pack_bits(&self.have))),
+            ("piece-count", BencodeValue::from(self.have.len() as i64)),
+            ("files", BencodeValue::from(files)),
+            ("partial", BencodeValue::from(partial)),
+        ])
+        .encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let dict = bencode::decode(bytes)?;
+        let int = |value: &BencodeValue, key| {
+            value
+                .get(key)
+                .and_then(BencodeValue::as_int)
+                .ok_or_else(|| anyhow::anyhow!("resume data has no {}", key))
+        };
+        let bytes = |value: &'_ BencodeValue, key| -> anyhow::Result<Vec<u8>> {
+            value
+                .get(key)
+                .and_then(BencodeValue::as_bytes)
+                .map(|b| b.to_vec())
+                .ok_or_else(|| anyhow::anyhow!("resume data has no {}", key))
+        };
+        let list = |key| match dict.get(key) {
+            Some(BencodeValue::List(list)) => Ok(list),
+            _ => Err(anyhow::anyhow!("resume data has no {}", key)),
+        };
+
+        let info_hash = bytes(&dict, "info-hash")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("bad info hash in resume data"))?;
+        let n_pieces = int(&dict, "piece-count")? as usize;
+        let have = unpack_bits(&bytes(&dict, "pieces")?, n_pieces)?;
+        let files = list("files")?
+            .iter()
+            .map(|file| {
+                Ok(FileStamp {
+                    length: int(file, "length")? as u64,
+                    mtime: int(file, "mtime")?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let partial = list("partial")?
+            .iter()
+            .map(|piece| {
+                let blocks = bytes(piece, "blocks")?;
+                let n_blocks = blocks.len() * 8;
+                Ok((int(piece, "piece")? as u32, unpack_bits(&blocks, n_blocks)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            info_hash,
+            have,
+            files,
+            partial,
+        })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::decode(&std::fs::read(path)?)
+    }
+
+    /// Writes the file next to its final place first, so a crash halfway
+    /// never leaves a broken one behind.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("resume.tmp");
+        std::fs::write(&tmp, self.encode())?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    Bitfield::from(bits).as_bytes().to_vec()
+}
+
+fn unpack_bits(bytes: &[u8], n: usize) -> anyhow::Result<Vec<bool>> {
+    let bits = Bitfield::from_bytes(bytes, n)?;
+    Ok((0..n).map(|i| bits.get(i)).collect())
+}
@@ -0,0 +1,430 @@
+use crate::bitfield::Bitfield;
+use crate::torrent::{BlockState, DownloadState, Info, Piece, DEFAULT_BLOCK_SIZE};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// pieces picked at random before switching to rarest first, so we quickly
+// have something to trade
+const RANDOM_FIRST_PIECES: usize = 4;
+// in streaming mode, this many pieces ahead of the cursor get deadlines
+const STREAM_WINDOW: u32 = 16;
+// pieces due within this long may have their blocks handed to a faster peer
+const URGENT: Duration = Duration::from_secs(2);
+
+/// Identifies a peer connection within one download.
+pub type PeerKey = usize;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BlockRequest {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// A piece that is partly downloaded: which blocks are in, and the piece's
+/// data with those blocks filled in.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartialPiece {
+    pub index: u32,
+    pub blocks: Vec<bool>,
+    pub data: Vec<u8>,
+}
+
+/// Decides which blocks to ask which peer for and puts pieces back together.
+/// Peers share one scheduler, so every block is only requested from one of them.
+pub struct Scheduler {
+    pieces: Vec<Piece>,
+    // pieces we actually want, everything else is left alone
+    wanted: Vec<bool>,
+    // how many connected peers have each piece
+    availability: Vec<u32>,
+    random_first: usize,
+    // streaming mode: pieces from here on are picked in order
+    cursor: Option<u32>,
+    // download rates in bytes per second, to tell slow peers from fast ones
+    peer_rates: HashMap<PeerKey, f64>,
+    // endgame: blocks asked for again from another peer than the one holding them
+    duplicates: Vec<(PeerKey, BlockRequest)>,
+}
+impl Scheduler {
+    pub fn new(info: &Info, wanted: impl IntoIterator<Item = u32>) -> Self {
+        let n_pieces = info.n_pieces();
+        let pieces = (0..n_pieces)
+            .map(|index| Piece::new(index, info.piece_size(index)))
+            .collect();
+        let mut want = vec![false; n_pieces as usize];
+        for index in wanted {
+            want[index as usize] = true;
+        }
+        Self {
+            pieces,
+            wanted: want,
+            availability: vec![0; n_pieces as usize],
+            random_first: RANDOM_FIRST_PIECES,
+            cursor: None,
+            peer_rates: HashMap::new(),
+            duplicates: Vec::new(),
+        }
+    }
+
+    /// Sets how many pieces are picked at random before going rarest first.
+    #[allow(dead_code)]
+    pub fn with_random_first(mut self, pieces: usize) -> Self {
+        self.random_first = pieces;
+        self
+    }
+
+    /// Counts a peer's bitfield towards piece availability.
+    pub fn add_availability(&mut self, has: &Bitfield) {
+        for index in has.ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Undoes `add_availability`, e.g. when the peer goes away.
+    pub fn remove_availability(&mut self, has: &Bitfield) {
+        for index in has.ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// A peer announced a new piece with a have message.
+    pub fn piece_available(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Moves the playback cursor to `cursor`. The next STREAM_WINDOW pieces are
+    /// due `piece_time` apart, starting now; everything else loses its deadline.
+    pub fn stream_from(&mut self, cursor: u32, piece_time: Duration, now: Instant) {
+        self.cursor = Some(cursor);
+        for piece in self.pieces.iter_mut() {
+            piece.deadline = match piece.index.checked_sub(cursor) {
+                Some(ahead) if ahead < STREAM_WINDOW => Some(now + piece_time * ahead),
+                _ => None,
+            };
+        }
+    }
+
+    pub fn set_peer_rate(&mut self, peer: PeerKey, rate: f64) {
+        self.peer_rates.insert(peer, rate);
+    }
+
+    /// Puts back blocks of a piece from an earlier run.
+    pub fn resume_piece(&mut self, partial: PartialPiece) {
+        let Some(piece) = self.pieces.get_mut(partial.index as usize) else {
+            return;
+        };
+        if !matches!(piece.state, DownloadState::Zero)
+            || partial.blocks.len() < piece.blocks.len()
+            || partial.data.len() != piece.size as usize
+            // a whole piece would never be checked
+            || partial.blocks[..piece.blocks.len()].iter().all(|&b| b)
+        {
+            return;
+        }
+        piece.start();
+        piece.data = partial.data;
+        for (block, &received) in piece.blocks.iter_mut().zip(&partial.blocks) {
+            if received {
+                *block = BlockState::Received(None);
+            }
+        }
+    }
+
+    /// The pieces that are started, with the blocks that are in so far.
+    pub fn partial_pieces(&self) -> Vec<PartialPiece> {
+        self.pieces
+            .iter()
+            .filter(|piece| matches!(piece.state, DownloadState::Partial))
+            .filter(|piece| !piece.data.is_empty())
+            .filter(|piece| piece.blocks.iter().any(BlockState::is_received))
+            .map(|piece| PartialPiece {
+                index: piece.index,
+                blocks: piece.blocks.iter().map(BlockState::is_received).collect(),
+                data: piece.data.clone(),
+            })
+            .collect()
+    }
+
+    /// True once every wanted piece has been verified.
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .zip(&self.wanted)
+            .all(|(piece, &wanted)| !wanted || matches!(piece.state, DownloadState::Complete))
+    }
+
+    /// The endgame starts once every block we still need has been requested.
+    pub fn in_endgame(&self) -> bool {
+        self.pieces
+            .iter()
+            .zip(&self.wanted)
+            .all(|(piece, &wanted)| !wanted || !piece.blocks.contains(&BlockState::Missing))
+    }
+
+    /// True if the block is in, no matter who sent it.
+    pub fn block_done(&self, request: &BlockRequest) -> bool {
+        let block = (request.begin / DEFAULT_BLOCK_SIZE) as usize;
+        self.pieces[request.piece as usize]
+            .blocks
+            .get(block)
+            .is_some_and(BlockState::is_received)
+    }
+
+    /// True if more than one peer was asked for this block.
+    pub fn is_duplicated(&self, request: &BlockRequest) -> bool {
+        self.duplicates.iter().any(|(_, r)| r == request)
+    }
+
+    /// Picks the next block to request from `peer`, which has the pieces in `has`.
+    /// Pieces with a deadline go first, and when they are due soon a faster
+    /// peer may take over their blocks from a slower one. Then pieces that are
+    /// already started are finished, so few pieces are half done at any time.
+    /// New pieces are picked in order from the cursor when streaming, otherwise
+    /// at random for the first few and then rarest first. In the endgame, blocks
+    /// other peers are still working on are handed out once more. A peer is kept
+    /// away from pieces it sent bad data for, unless there is nothing else it could do.
+    pub fn next_block(&mut self, peer: PeerKey, has: &Bitfield) -> Option<BlockRequest> {
+        let now = Instant::now();
+        self.pick(peer, has, false, now)
+            .or_else(|| self.pick(peer, has, true, now))
+    }
+
+    fn pick(
+        &mut self,
+        peer: PeerKey,
+        has: &Bitfield,
+        allow_bad: bool,
+        now: Instant,
+    ) -> Option<BlockRequest> {
+        let candidate = |piece: &Piece| {
+            self.wanted[piece.index as usize]
+                && has.get(piece.index as usize)
+                && (allow_bad || !piece.bad_peers.contains(&peer))
+                && piece.open_to(peer)
+        };
+        let missing = |piece: &Piece| piece.blocks.contains(&BlockState::Missing);
+
+        let due = self
+            .pieces
+            .iter()
+            .filter(|piece| piece.deadline.is_some() && candidate(piece) && missing(piece))
+            .min_by_key(|piece| piece.deadline)
+            .map(|piece| piece.index as usize);
+        if due.is_none() {
+            if let Some((index, block)) = self.slow_block(peer, candidate, now) {
+                let piece = &mut self.pieces[index];
+                let request = piece.block_request(block as u32);
+                // the slow peer is still on it, and gets it back if the fast one drops it
+                if let BlockState::Requested(old) = piece.blocks[block] {
+                    self.duplicates.push((old, request));
+                }
+                piece.blocks[block] = BlockState::Requested(peer);
+                return Some(request);
+            }
+        }
+        let started = self.pieces.iter().position(|piece| {
+            matches!(piece.state, DownloadState::Partial) && candidate(piece) && missing(piece)
+        });
+        let index = due.or(started).or_else(|| {
+            let fresh: Vec<usize> = self
+                .pieces
+                .iter()
+                .filter(|piece| matches!(piece.state, DownloadState::Zero) && candidate(piece))
+                .map(|piece| piece.index as usize)
+                .collect();
+            let done = self
+                .pieces
+                .iter()
+                .filter(|piece| matches!(piece.state, DownloadState::Complete))
+                .count();
+            if let Some(cursor) = self.cursor {
+                // whatever comes next after the cursor, then anything before it
+                fresh
+                    .iter()
+                    .find(|&&i| i >= cursor as usize)
+                    .or(fresh.first())
+                    .copied()
+            } else if done < self.random_first {
+                fresh.choose(&mut rand::thread_rng()).copied()
+            } else {
+                // among the equally rare ones any will do, and picking at random
+                // keeps every client from going for the same piece first
+                let rarest = fresh.iter().map(|&i| self.availability[i]).min()?;
+                let rarest: Vec<usize> = fresh
+                    .into_iter()
+                    .filter(|&i| self.availability[i] == rarest)
+                    .collect();
+                rarest.choose(&mut rand::thread_rng()).copied()
+            }
+        });
+        let Some(index) = index else {
+            let request = self.endgame_block(peer, candidate)?;
+            self.duplicates.push((peer, request));
+            return Some(request);
+        };
+
+        let piece = &mut self.pieces[index];
+        piece.start();
+        let block = piece
+            .blocks
+            .iter()
+            .position(|b| *b == BlockState::Missing)?;
+        piece.blocks[block] = BlockState::Requested(peer);
+        Some(piece.block_request(block as u32))
+    }
+
+    /// Finds a block of a piece due within URGENT that is waiting on a peer
+    /// slower than `peer`, earliest deadline first.
+    fn slow_block(
+        &self,
+        peer: PeerKey,
+        candidate: impl Fn(&Piece) -> bool,
+        now: Instant,
+    ) -> Option<(usize, usize)> {
+        let rate = |peer| self.peer_rates.get(&peer).copied().unwrap_or(0.0);
+        let mut urgent: Vec<&Piece> = self
+            .pieces
+            .iter()
+            .filter(|piece| piece.deadline.is_some_and(|d| d <= now + URGENT) && candidate(piece))
+            .collect();
+        urgent.sort_by_key(|piece| piece.deadline);
+        urgent.into_iter().find_map(|piece| {
+            let block = piece.blocks.iter().position(|b| {
+                matches!(b, BlockState::Requested(other) if *other != peer && rate(*other) < rate(peer))
+            })?;
+            Some((piece.index as usize, block))
+        })
+    }
+
+    /// Finds a block that another peer has been asked for, but `peer` hasn't yet.
+    fn endgame_block(
+        &self,
+        peer: PeerKey,
+        candidate: impl Fn(&Piece) -> bool,
+    ) -> Option<BlockRequest> {
+        if !self.in_endgame() {
+            return None;
+        }
+        self.pieces
+            .iter()
+            .filter(|piece| candidate(piece))
+            .find_map(|piece| {
+                piece.blocks.iter().enumerate().find_map(|(block, state)| {
+                    let request = piece.block_request(block as u32);
+                    let theirs = matches!(state, BlockState::Requested(other) if *other != peer);
+                    (theirs && !self.duplicates.contains(&(peer, request))).then_some(request)
+                })
+            })
+    }
+
+    /// Stores a block `peer` sent. Returns the whole piece once its last block is in,
+    /// ready to be verified. Blocks we didn't ask for, or already have, are ignored.
+    pub fn block_received(
+        &mut self,
+        peer: PeerKey,
+        piece: u32,
+        begin: u32,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let piece = self.pieces.get_mut(piece as usize)?;
+        if !begin.is_multiple_of(DEFAULT_BLOCK_SIZE) {
+            return None;
+        }
+        let block = (begin / DEFAULT_BLOCK_SIZE) as usize;
+        if !matches!(piece.blocks.get(block), Some(BlockState::Requested(_)))
+            || data.len() as u32 != piece.block_request(block as u32).length
+        {
+            return None;
+        }
+
+        piece.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+        piece.blocks[block] = BlockState::Received(Some(peer));
+        let request = piece.block_request(block as u32);
+        self.duplicates.retain(|(_, r)| *r != request);
+        if piece.blocks.iter().all(BlockState::is_received) {
+            Some(std::mem::take(&mut piece.data))
+        } else {
+            None
+        }
+    }
+
+    pub fn piece_verified(&mut self, index: u32) {
+        let piece = &mut self.pieces[index as usize];
+        piece.state = DownloadState::Complete;
+        piece.data = Vec::new();
+        piece.deadline = None;
+    }
+
+    /// Throws a corrupt piece away so it is downloaded again, and keeps
+    /// everyone who sent a block of it away from it. Returns the peer to
+    /// blame, if one peer sent the whole piece. With several of them there
+    /// is no telling whose blocks were bad, so the next copy has to come
+    /// from a single peer.
+    pub fn piece_failed(&mut self, index: u32) -> Option<PeerKey> {
+        let piece = &mut self.pieces[index as usize];
+        let senders: Vec<Option<PeerKey>> = piece
+            .blocks
+            .iter()
+            .filter_map(|b| match b {
+                BlockState::Received(sender) => Some(*sender),
+                _ => None,
+            })
+            .collect();
+        for &peer in senders.iter().flatten() {
+            if !piece.bad_peers.contains(&peer) {
+                piece.bad_peers.push(peer);
+            }
+        }
+        piece.reset();
+        self.duplicates.retain(|(_, r)| r.piece != index);
+        match senders.first() {
+            Some(&Some(peer)) if senders.iter().all(|&s| s == Some(peer)) => Some(peer),
+            _ => {
+                piece.one_peer = true;
+                None
+            }
+        }
+    }
+
+    /// Gives a request back, e.g. when the peer choked us before answering it.
+    /// Blocks that went to another peer in the meantime stay with that peer.
+    pub fn release(&mut self, peer: PeerKey, request: &BlockRequest) {
+        self.duplicates.retain(|d| *d != (peer, *request));
+        let piece = &mut self.pieces[request.piece as usize];
+        let block = (request.begin / DEFAULT_BLOCK_SIZE) as usize;
+        if piece.blocks.get(block) == Some(&BlockState::Requested(peer)) {
+            hand_over(&mut self.duplicates, piece, block);
+        }
+    }
+
+    /// Gives back every request of a peer that went away.
+    pub fn release_peer(&mut self, peer: PeerKey) {
+        self.peer_rates.remove(&peer);
+        self.duplicates.retain(|(p, _)| *p != peer);
+        for piece in self.pieces.iter_mut() {
+            for block in 0..piece.blocks.len() {
+                if piece.blocks[block] == BlockState::Requested(peer) {
+                    hand_over(&mut self.duplicates, piece, block);
+                }
+            }
+        }
+    }
+}
+
+/// Passes a block on to a peer that was asked for it as well, or marks it
+/// missing when there is none.
+fn hand_over(duplicates: &mut Vec<(PeerKey, BlockRequest)>, piece: &mut Piece, block: usize) {
+    let request = piece.block_request(block as u32);
+    piece.blocks[block] = match duplicates.iter().position(|(_, r)| *r == request) {
+        Some(i) => BlockState::Requested(duplicates.remove(i).0),
+        None => BlockState::Missing,
+    };
+}
@@ -0,0 +1,272 @@
+use crate::bitfield::Bitfield;
+use crate::choker::{Choker, RECHOKE_INTERVAL, UNCHOKE_SLOTS};
+use crate::message::Message;
+use crate::peer::{allowed_fast_set, Peer, ALLOWED_FAST_COUNT, MAX_REQUEST_QUEUE};
+use crate::scheduler::{BlockRequest, PeerKey};
+use crate::storage::Storage;
+use crate::torrent::Info;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+// most peers we upload to at once
+const MAX_CONNECTIONS: usize = 50;
+// peers that stay quiet for this long are dropped
+const PEER_TIMEOUT: Duration = Duration::from_secs(120);
+// nobody asks for more than this in one request, bigger ones get the peer dropped
+const MAX_BLOCK_LENGTH: u32 = 1 << 17;
+// how long to wait before accepting again after it failed, e.g. when we're
+// out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A torrent we hand out pieces of.
+pub struct Seed {
+    info: Info,
+    // pieces that are verified and in storage
+    have: Bitfield,
+    storage: Mutex<Box<dyn Storage>>,
+    choker: Mutex<Choker>,
+    // pinged when the choker unchoked someone else
+    rechoked: watch::Sender<()>,
+}
+impl Seed {
+    fn rechoke(&self, now: Instant) {
+        if self.choker.lock().unwrap().rechoke(now) {
+            self.rechoked.send_replace(());
+        }
+    }
+}
+
+/// Accepts connections from other peers and uploads the torrents it holds.
+pub struct Server {
+    peer_id: [u8; 20],
+    torrents: HashMap<[u8; 20], Arc<Seed>>,
+}
+impl Server {
+    pub fn new(peer_id: [u8; 20]) -> Self {
+        Self {
+            peer_id,
+            torrents: HashMap::new(),
+        }
+    }
+
+    /// Serves the torrent `info`. Only the pieces in `have` are handed out.
+    pub fn with_torrent(
+        mut self,
+        info: Info,
+        info_hash: [u8; 20],
+        have: Bitfield,
+        storage: Box<dyn Storage>,
+    ) -> Self {
+        let seed = Seed {
+            info,
+            have,
+            storage: Mutex::new(storage),
+            choker: Mutex::new(Choker::new(UNCHOKE_SLOTS)),
+            rechoked: watch::channel(()).0,
+        };
+        self.torrents.insert(info_hash, Arc::new(seed));
+        self
+    }
+
+    /// Listens on `port` on every interface.
+    pub async fn listen(self, port: u16) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        self.serve(listener).await
+    }
+
+    /// Serves peers that connect to `listener`, forever. Accept errors are
+    /// usually about one connection or a passing shortage, so they're only logged.
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        let torrents = Arc::new(self.torrents);
+        let mut tasks = JoinSet::new();
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = rechoke.tick() => {
+                    for seed in torrents.values() {
+                        seed.rechoke(Instant::now());
+                    }
+                }
+                accepted = listener.accept() => {
+                    let (stream, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            println!("accept failed: {}", e);
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    if tasks.len() >= MAX_CONNECTIONS {
+                        continue;
+                    }
+                    let torrents = torrents.clone();
+                    let peer_id = self.peer_id;
+                    tasks.spawn(async move {
+                        let peer = Peer::accept(stream);
+                        (addr, serve_peer(peer, &torrents, peer_id).await)
+                    });
+                }
+                Some(res) = tasks.join_next() => match res {
+                    Ok((addr, Err(e))) => println!("peer {} disconnected: {}", addr, e),
+                    Ok((_, Ok(()))) => {}
+                    Err(e) => println!("peer task failed: {}", e),
+                },
+            }
+        }
+    }
+}
+
+/// Uploads to one peer until it hangs up or misbehaves.
+async fn serve_peer(
+    mut peer: Peer,
+    torrents: &HashMap<[u8; 20], Arc<Seed>>,
+    peer_id: [u8; 20],
+) -> anyhow::Result<()> {
+    let handshake = peer
+        .accept_handshake(|info_hash| torrents.contains_key(info_hash), peer_id)
+        .await?;
+    let seed = &torrents[&handshake.info_hash];
+    let key = seed.choker.lock().unwrap().add_peer();
+    let result = peer_loop(&mut peer, seed, &handshake.info_hash, key).await;
+    seed.choker.lock().unwrap().remove_peer(key);
+    result
+}
+
+async fn peer_loop(
+    peer: &mut Peer,
+    seed: &Seed,
+    info_hash: &[u8; 20],
+    key: PeerKey,
+) -> anyhow::Result<()> {
+    peer.send_bitfield(&seed.have).await?;
+    if peer.supports_extensions {
+        peer.send_extended_handshake().await?;
+    }
+    // pieces the peer can have even while choked, only worked out for IPv4
+    let mut allowed_fast = Vec::new();
+    if let (true, SocketAddr::V4(addr)) = (peer.supports_fast, peer.addr()?) {
+        let n_pieces = seed.info.n_pieces();
+        allowed_fast = allowed_fast_set(*addr.ip(), info_hash, n_pieces, ALLOWED_FAST_COUNT);
+        allowed_fast.retain(|&index| seed.have.get(index as usize));
+        for &index in &allowed_fast {
+            peer.send(&Message::AllowedFast(index)).await?;
+        }
+    }
+
+    let mut requests: VecDeque<BlockRequest> = VecDeque::new();
+    let mut rechoked = seed.rechoked.subscribe();
+    loop {
+        // messages that are already here go first, so a cancel can still
+        // catch its request in the queue
+        let msg = tokio::select! {
+            biased;
+            msg = tokio::time::timeout(PEER_TIMEOUT, peer.read_message()) => Some(msg??),
+            Ok(()) = rechoked.changed() => {
+                let unchoked = seed.choker.lock().unwrap().is_unchoked(key);
+                peer.set_choking(!unchoked).await?;
+                if peer.am_choking {
+                    // the peer knows to ask again once it's unchoked, with the
+                    // fast extension it's told which requests are gone
+                    let (keep, rejected) = requests
+                        .drain(..)
+                        .partition(|r| allowed_fast.contains(&r.piece));
+                    requests = keep;
+                    if peer.supports_fast {
+                        for request in rejected {
+                            peer.send(&Message::RejectRequest(request)).await?;
+                        }
+                    }
+                }
+                continue;
+            }
+            _ = std::future::ready(()), if !requests.is_empty() => None,
+        };
+        let Some(msg) = msg else {
+            let request = requests.pop_front().unwrap();
+            let block = seed.storage.lock().unwrap().read_block(
+                &seed.info,
+                request.piece,
+                request.begin,
+                request.length,
+            )?;
+            let length = block.len();
+            let piece = Message::Piece {
+                index: request.piece,
+                begin: request.begin,
+                block,
+            };
+            peer.send(&piece).await?;
+            seed.choker.lock().unwrap().uploaded(key, length);
+            continue;
+        };
+
+        if msg.needs_fast_extension() && !peer.supports_fast {
+            anyhow::bail!("sent {:?} without the fast extension", msg);
+        }
+        match msg {
+            Message::Interested | Message::NotInterested => {
+                let unchoked = seed
+                    .choker
+                    .lock()
+                    .unwrap()
+                    .set_interested(key, peer.peer_interested);
+                if unchoked {
+                    peer.set_choking(false).await?;
+                }
+            }
+            Message::Request(request) => {
+                check_request(&seed.info, &request)?;
+                // requests while choked, or past the queue we told the peer
+                // about, are dropped, or rejected with the fast extension
+                if (!peer.am_choking || allowed_fast.contains(&request.piece))
+                    && seed.have.get(request.piece as usize)
+                    && requests.len() < MAX_REQUEST_QUEUE as usize
+                {
+                    requests.push_back(request);
+                } else if peer.supports_fast {
+                    peer.send(&Message::RejectRequest(request)).await?;
+                }
+            }
+            Message::Cancel(request) => {
+                let queued = requests.len();
+                requests.retain(|r| *r != request);
+                // a cancelled request still gets an answer with the fast extension
+                if peer.supports_fast && requests.len() < queued {
+                    peer.send(&Message::RejectRequest(request)).await?;
+                }
+            }
+            Message::Extended { id, payload } => peer.handle_extended(id, &payload).await?,
+            // we have nothing to download from them, and the DHT finds its
+            // nodes on its own
+            Message::Choke
+            | Message::Unchoke
+            | Message::Have(_)
+            | Message::Bitfield(_)
+            | Message::Piece { .. }
+            | Message::Port(_)
+            | Message::SuggestPiece(_)
+            | Message::HaveAll
+            | Message::HaveNone
+            | Message::RejectRequest(_)
+            | Message::AllowedFast(_) => {}
+            Message::KeepAlive | Message::Unknown { .. } => {}
+        }
+    }
+}
+
+/// Checks a request fits the torrent.
+fn check_request(info: &Info, request: &BlockRequest) -> anyhow::Result<()> {
+    if request.piece >= info.n_pieces()
+        || request.length == 0
+        || request.length > MAX_BLOCK_LENGTH
+        || request.begin as u64 + request.length as u64 > info.piece_size(request.piece) as u64
+    {
+        anyhow::bail!("bad request {:?}", request);
+    }
+    Ok(())
+}
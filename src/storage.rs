@@ -0,0 +1,273 @@
+use crate::torrent::{FileEntry, Info};
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// Somewhere to keep the data of a torrent. Everything is addressed by offsets
+/// in the torrent's byte space, the block and piece helpers build on that.
+pub trait Storage: Send {
+    /// Reads `length` bytes starting at `offset`.
+    fn read_at(&mut self, offset: u64, length: usize) -> std::io::Result<Vec<u8>>;
+
+    /// Writes `data` starting at `offset`.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()>;
+
+    /// Makes room for the whole torrent up front. Existing data is kept.
+    fn preallocate(&mut self) -> std::io::Result<()>;
+
+    /// Makes sure everything written so far is stored for good.
+    fn flush(&mut self) -> std::io::Result<()>;
+
+    fn write_block(
+        &mut self,
+        info: &Info,
+        piece: u32,
+        begin: u32,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        self.write_at(piece as u64 * info.piece_length as u64 + begin as u64, data)
+    }
+
+    fn read_block(
+        &mut self,
+        info: &Info,
+        piece: u32,
+        begin: u32,
+        length: u32,
+    ) -> std::io::Result<Vec<u8>> {
+        self.read_at(
+            piece as u64 * info.piece_length as u64 + begin as u64,
+            length as usize,
+        )
+    }
+
+    fn read_piece(&mut self, info: &Info, index: u32) -> std::io::Result<Vec<u8>> {
+        self.read_block(info, index, 0, info.piece_size(index))
+    }
+
+    /// Checks the piece at `index` against its hash.
+    fn verify(&mut self, info: &Info, index: u32) -> std::io::Result<bool> {
+        Ok(info.verify_piece(index, &self.read_piece(info, index)?))
+    }
+
+    /// Hashes whatever is stored already, and says which pieces are good.
+    fn check_pieces(&mut self, info: &Info) -> std::io::Result<Vec<bool>> {
+        (0..info.n_pieces())
+            .map(|index| self.verify(info, index))
+            .collect()
+    }
+}
+
+/// Splits the range at `offset` over the files of `layout`: the index of the
+/// file, where in the file the part starts, and where it is in the range.
+fn spans(
+    layout: &[FileEntry],
+    offset: u64,
+    length: usize,
+) -> impl Iterator<Item = (usize, u64, Range<usize>)> + '_ {
+    let end = offset + length as u64;
+    layout.iter().enumerate().filter_map(move |(i, entry)| {
+        let file_end = entry.offset + entry.length;
+        if file_end <= offset || entry.offset >= end {
+            return None;
+        }
+        let start = offset.max(entry.offset);
+        let stop = end.min(file_end);
+        Some((
+            i,
+            start - entry.offset,
+            (start - offset) as usize..(stop - offset) as usize,
+        ))
+    })
+}
+
+fn open_files(layout: &[FileEntry]) -> std::io::Result<Vec<File>> {
+    let mut files = Vec::with_capacity(layout.len());
+    for entry in layout {
+        if let Some(parent) = entry.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        files.push(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&entry.path)?,
+        );
+    }
+    Ok(files)
+}
+
+fn set_lengths(layout: &[FileEntry], files: &[File]) -> std::io::Result<()> {
+    for (entry, file) in layout.iter().zip(files) {
+        // only touch files that need it, so their mtime stays put otherwise
+        if file.metadata()?.len() != entry.length {
+            file.set_len(entry.length)?;
+        }
+    }
+    Ok(())
+}
+
+/// The files of a torrent on disk, opened once and written with plain seeks and writes.
+pub struct FileStorage {
+    layout: Vec<FileEntry>,
+    files: Vec<File>,
+}
+impl FileStorage {
+    /// Opens every file of `layout`, creating it and the directories above it
+    /// if needed. Existing data is kept.
+    pub fn open(layout: &[FileEntry]) -> std::io::Result<Self> {
+        Ok(Self {
+            layout: layout.to_vec(),
+            files: open_files(layout)?,
+        })
+    }
+}
+impl Storage for FileStorage {
+    fn read_at(&mut self, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        for (i, start, range) in spans(&self.layout, offset, length) {
+            self.files[i].seek(SeekFrom::Start(start))?;
+            self.files[i].read_exact(&mut data[range])?;
+        }
+        Ok(data)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        for (i, start, range) in spans(&self.layout, offset, data.len()) {
+            self.files[i].seek(SeekFrom::Start(start))?;
+            self.files[i].write_all(&data[range])?;
+        }
+        Ok(())
+    }
+
+    fn preallocate(&mut self) -> std::io::Result<()> {
+        set_lengths(&self.layout, &self.files)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for file in &self.files {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// The files of a torrent on disk, mapped into memory. Files are set to their
+/// full size when they are opened, a mapping can't grow.
+pub struct MmapStorage {
+    layout: Vec<FileEntry>,
+    // empty files can't be mapped, and don't need to be
+    maps: Vec<Option<MmapMut>>,
+}
+impl MmapStorage {
+    pub fn open(layout: &[FileEntry]) -> std::io::Result<Self> {
+        let files = open_files(layout)?;
+        set_lengths(layout, &files)?;
+        let maps = layout
+            .iter()
+            .zip(&files)
+            .map(|(entry, file)| match entry.length {
+                0 => Ok(None),
+                // other processes changing the file under us is on them
+                _ => unsafe { MmapMut::map_mut(file) }.map(Some),
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            layout: layout.to_vec(),
+            maps,
+        })
+    }
+}
+impl Storage for MmapStorage {
+    fn read_at(&mut self, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        for (i, start, range) in spans(&self.layout, offset, length) {
+            if let Some(map) = &self.maps[i] {
+                let start = start as usize;
+                data[range.clone()].copy_from_slice(&map[start..start + range.len()]);
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        for (i, start, range) in spans(&self.layout, offset, data.len()) {
+            if let Some(map) = &mut self.maps[i] {
+                let start = start as usize;
+                map[start..start + range.len()].copy_from_slice(&data[range]);
+            }
+        }
+        Ok(())
+    }
+
+    fn preallocate(&mut self) -> std::io::Result<()> {
+        // done in `open` already
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for map in self.maps.iter().flatten() {
+            map.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the whole torrent in memory, e.g. for tests or to hand it on to
+/// something that isn't a file.
+#[allow(dead_code)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+    length: usize,
+}
+#[allow(dead_code)]
+impl MemoryStorage {
+    pub fn new(length: u64) -> Self {
+        Self {
+            data: Vec::new(),
+            length: length as usize,
+        }
+    }
+
+    pub fn into_inner(mut self) -> Vec<u8> {
+        self.data.resize(self.length, 0);
+        self.data
+    }
+
+    fn range(&self, offset: u64, length: usize) -> std::io::Result<Range<usize>> {
+        let start = offset as usize;
+        match start.checked_add(length) {
+            Some(end) if end <= self.length => Ok(start..end),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "past the end of the torrent",
+            )),
+        }
+    }
+}
+impl Storage for MemoryStorage {
+    fn read_at(&mut self, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+        let range = self.range(offset, length)?;
+        self.data.resize(self.length, 0);
+        Ok(self.data[range].to_vec())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let range = self.range(offset, data.len())?;
+        self.data.resize(self.length, 0);
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn preallocate(&mut self) -> std::io::Result<()> {
+        self.data.resize(self.length, 0);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
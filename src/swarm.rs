@@ -0,0 +1,633 @@
+use crate::bitfield::Bitfield;
+use crate::message::Message;
+use crate::peer::Peer;
+use crate::pex::{PexState, UtPex, FLAG_REACHABLE, FLAG_SEED, PEX_INTERVAL};
+use crate::scheduler::{BlockRequest, PartialPiece, PeerKey, Scheduler};
+use crate::torrent::{Info, DEFAULT_BLOCK_SIZE};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+// how many peers we download from at once
+const MAX_CONNECTIONS: usize = 30;
+// how many corrupt pieces a peer can send before it is dropped
+const MAX_HASH_FAILURES: u32 = 2;
+// peers that don't send anything for this long are dropped
+const PEER_TIMEOUT: Duration = Duration::from_secs(120);
+// verified pieces waiting to be written; peers wait when it's full, so a slow
+// disk doesn't pile pieces up in memory
+const PIECE_QUEUE: usize = 8;
+// upper bound on the requests we keep out with a single peer
+pub const DEFAULT_MAX_REQUESTS: usize = 128;
+// the window never shrinks below this, so a new peer gets a chance to show its speed
+const MIN_REQUESTS: usize = 4;
+// keep about this much of a peer's data requested ahead of time
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+// how often the download rate of a peer is sampled
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+// peers heard about over ut_pex that haven't made it into the pool yet
+const DISCOVERED_QUEUE: usize = 256;
+
+// resolves when the download should stop early, e.g. on Ctrl-C
+type Stop = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// What a download reports back as it goes.
+pub enum Progress {
+    /// A piece passed its hash check.
+    Piece(u32, Vec<u8>),
+    /// The blocks of unfinished pieces, so they can be saved. Only sent with
+    /// `with_checkpoints`, every so often and once more when the download stops.
+    Partial(Vec<PartialPiece>),
+}
+
+/// The block requests we have out with one peer. How many we keep out depends
+/// on how fast the peer is: enough to cover QUEUE_TIME at its current rate,
+/// but never more than `max` or than the peer's `reqq`.
+pub struct Pipeline {
+    outstanding: Vec<BlockRequest>,
+    max: usize,
+    peer_limit: Option<usize>,
+    // bytes per second, smoothed
+    rate: f64,
+    sample_bytes: u64,
+    sample_start: Instant,
+}
+impl Pipeline {
+    pub fn new(max: usize) -> Self {
+        Self {
+            outstanding: Vec::new(),
+            max: max.max(1),
+            peer_limit: None,
+            rate: 0.0,
+            sample_bytes: 0,
+            sample_start: Instant::now(),
+        }
+    }
+
+    /// Applies the `reqq` from the peer's extended handshake.
+    pub fn set_peer_limit(&mut self, reqq: u32) {
+        self.peer_limit = Some((reqq as usize).max(1));
+    }
+
+    pub fn window(&self) -> usize {
+        let limit = self.peer_limit.map_or(self.max, |l| l.min(self.max));
+        let wanted = (self.rate * QUEUE_TIME.as_secs_f64() / DEFAULT_BLOCK_SIZE as f64).ceil();
+        (wanted as usize).max(MIN_REQUESTS).min(limit)
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.outstanding.len() < self.window()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn push(&mut self, request: BlockRequest) {
+        self.outstanding.push(request);
+    }
+
+    /// Finds the request a piece message answers and takes it off the queue.
+    pub fn take(&mut self, piece: u32, begin: u32, length: u32) -> Option<BlockRequest> {
+        let i = self
+            .outstanding
+            .iter()
+            .position(|r| r.piece == piece && r.begin == begin && r.length == length)?;
+        Some(self.outstanding.remove(i))
+    }
+
+    /// Takes out the requests that `done` says we don't need anymore.
+    pub fn cancel(&mut self, done: impl Fn(&BlockRequest) -> bool) -> Vec<BlockRequest> {
+        let (cancelled, outstanding) = std::mem::take(&mut self.outstanding)
+            .into_iter()
+            .partition(|r| done(r));
+        self.outstanding = outstanding;
+        cancelled
+    }
+
+    /// Hands back every outstanding request, e.g. when we get choked.
+    pub fn drain(&mut self) -> Vec<BlockRequest> {
+        std::mem::take(&mut self.outstanding)
+    }
+
+    /// Counts received bytes towards the peer's download rate.
+    pub fn record(&mut self, bytes: usize, now: Instant) {
+        self.sample_bytes += bytes as u64;
+        let elapsed = now.saturating_duration_since(self.sample_start);
+        if elapsed >= RATE_INTERVAL {
+            let sample = self.sample_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate == 0.0 {
+                sample
+            } else {
+                0.7 * self.rate + 0.3 * sample
+            };
+            self.sample_bytes = 0;
+            self.sample_start = now;
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+/// What the peer connections of one download share.
+struct Shared {
+    info: Info,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    max_requests: usize,
+    scheduler: Mutex<Scheduler>,
+    // pinged when a block that several peers were asked for comes in
+    blocks_done: watch::Sender<()>,
+    // verified pieces go back to whoever runs the download
+    pieces: mpsc::Sender<(u32, Vec<u8>)>,
+    // the pieces we have, every peer gets told when it grows
+    have: watch::Sender<Bitfield>,
+    // the peers we're connected to with their ut_pex flags, passed on to
+    // other peers
+    connected: Mutex<HashMap<SocketAddr, u8>>,
+    // peers other peers told us about
+    discovered: mpsc::Sender<SocketAddr>,
+}
+
+/// Downloads from many peers at once. Every peer connection runs in its own
+/// task and asks the shared scheduler what to request next, so a peer that
+/// goes away only takes its outstanding requests with it.
+pub struct Swarm {
+    info: Info,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    max_requests: usize,
+    // streaming mode: the playback cursor, and how long one piece plays for
+    cursor: Option<(watch::Receiver<u32>, Duration)>,
+    // blocks we already have from an earlier run
+    partial: Vec<PartialPiece>,
+    // pieces we have to begin with
+    have: Option<Bitfield>,
+    checkpoints: Option<Duration>,
+    stop: Option<Stop>,
+}
+impl Swarm {
+    pub fn new(info: Info, info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            info,
+            info_hash,
+            peer_id,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            cursor: None,
+            partial: Vec::new(),
+            have: None,
+            checkpoints: None,
+            stop: None,
+        }
+    }
+
+    /// Caps how many requests are kept out with each peer.
+    pub fn with_max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// Switches to streaming mode: pieces are fetched in order from `cursor`,
+    /// and the ones just ahead of it get deadlines `piece_time` apart.
+    pub fn with_cursor(mut self, cursor: watch::Receiver<u32>, piece_time: Duration) -> Self {
+        self.cursor = Some((cursor, piece_time));
+        self
+    }
+
+    /// Starts off with blocks saved by an earlier run, so only the rest of
+    /// those pieces is downloaded.
+    pub fn with_partial(mut self, partial: Vec<PartialPiece>) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// The pieces we have already, peers are told about them when they connect.
+    pub fn with_have(mut self, have: Bitfield) -> Self {
+        self.have = Some(have);
+        self
+    }
+
+    /// Reports the state of unfinished pieces every `interval`, and when the
+    /// download stops.
+    pub fn with_checkpoints(mut self, interval: Duration) -> Self {
+        self.checkpoints = Some(interval);
+        self
+    }
+
+    /// Gives up on the download once `stop` resolves. With checkpoints, the
+    /// last one is still taken, so nothing that is in storage gets lost.
+    pub fn with_stop(mut self, stop: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.stop = Some(Box::pin(stop));
+        self
+    }
+
+    /// Downloads the `wanted` pieces from `peers`, connecting to up to
+    /// MAX_CONNECTIONS of them at a time. Peers learned over ut_pex join the
+    /// queue as they come in. Every piece is passed to `on_progress` once it
+    /// has passed its hash check.
+    pub async fn download(
+        self,
+        peers: Vec<SocketAddrV4>,
+        wanted: impl IntoIterator<Item = u32>,
+        mut on_progress: impl FnMut(Progress) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let wanted: Vec<u32> = wanted.into_iter().collect();
+        let mut remaining = wanted.len();
+        if remaining == 0 {
+            return Ok(());
+        }
+
+        let mut scheduler = Scheduler::new(&self.info, wanted);
+        for piece in self.partial {
+            scheduler.resume_piece(piece);
+        }
+        let (mut cursor, piece_time) = match self.cursor {
+            Some((cursor, piece_time)) => (Some(cursor), piece_time),
+            None => (None, Duration::ZERO),
+        };
+        if let Some(cursor) = &mut cursor {
+            scheduler.stream_from(*cursor.borrow_and_update(), piece_time, Instant::now());
+        }
+
+        let have = self
+            .have
+            .unwrap_or_else(|| Bitfield::new(self.info.n_pieces() as usize));
+        let (tx, mut rx) = mpsc::channel(PIECE_QUEUE);
+        let (discovered, mut discovered_rx) = mpsc::channel(DISCOVERED_QUEUE);
+        let shared = Arc::new(Shared {
+            have: watch::Sender::new(have),
+            scheduler: Mutex::new(scheduler),
+            blocks_done: watch::Sender::new(()),
+            info: self.info,
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            max_requests: self.max_requests,
+            pieces: tx,
+            connected: Mutex::new(HashMap::new()),
+            discovered,
+        });
+
+        // every address is tried once, however often we hear about it
+        let mut seen: HashSet<SocketAddr> = HashSet::new();
+        let mut pending: VecDeque<SocketAddr> = VecDeque::new();
+        for peer in peers {
+            if seen.insert(SocketAddr::V4(peer)) {
+                pending.push_back(SocketAddr::V4(peer));
+            }
+        }
+        let mut stop = self.stop;
+        let mut checkpoints = self
+            .checkpoints
+            .map(|every| tokio::time::interval_at(tokio::time::Instant::now() + every, every));
+        let mut tasks = JoinSet::new();
+        let mut next_key: PeerKey = 0;
+        let result = async {
+            while remaining > 0 {
+                while tasks.len() < MAX_CONNECTIONS {
+                    let Some(addr) = pending.pop_front() else {
+                        break;
+                    };
+                    tasks.spawn(run_peer(addr, next_key, shared.clone()));
+                    next_key += 1;
+                }
+                if tasks.is_empty() {
+                    // the last peer may have finished pieces on its way out
+                    while let Ok((index, piece)) = rx.try_recv() {
+                        on_progress(Progress::Piece(index, piece))?;
+                        shared
+                            .have
+                            .send_modify(|have| have.set(index as usize, true));
+                        remaining -= 1;
+                    }
+                    if remaining == 0 {
+                        break;
+                    }
+                    while let Ok(addr) = discovered_rx.try_recv() {
+                        if seen.insert(addr) {
+                            pending.push_back(addr);
+                        }
+                    }
+                    if !pending.is_empty() {
+                        continue;
+                    }
+                    anyhow::bail!("ran out of peers with {} pieces to go", remaining);
+                }
+
+                tokio::select! {
+                    Some((index, piece)) = rx.recv() => {
+                        on_progress(Progress::Piece(index, piece))?;
+                        // only now is it stored, and ours to announce
+                        shared.have.send_modify(|have| have.set(index as usize, true));
+                        remaining -= 1;
+                    }
+                    Some(index) = cursor_moved(&mut cursor) => {
+                        shared
+                            .scheduler
+                            .lock()
+                            .unwrap()
+                            .stream_from(index, piece_time, Instant::now());
+                    }
+                    Some(addr) = discovered_rx.recv() => {
+                        if seen.insert(addr) {
+                            pending.push_back(addr);
+                        }
+                    }
+                    _ = stopped(&mut stop) => {
+                        anyhow::bail!("stopped with {} pieces to go", remaining);
+                    }
+                    _ = tick(&mut checkpoints) => {
+                        let partial = shared.scheduler.lock().unwrap().partial_pieces();
+                        on_progress(Progress::Partial(partial))?;
+                    }
+                    Some(res) = tasks.join_next() => match res {
+                        Ok((addr, Err(e))) => println!("peer {} disconnected: {}", addr, e),
+                        Ok((_, Ok(()))) => {}
+                        Err(e) => println!("peer task failed: {}", e),
+                    },
+                }
+            }
+            Ok(())
+        }
+        .await;
+        tasks.abort_all();
+
+        if checkpoints.is_some() {
+            let partial = shared.scheduler.lock().unwrap().partial_pieces();
+            return result.and(on_progress(Progress::Partial(partial)));
+        }
+        result
+    }
+}
+
+/// Waits for the download to be stopped, forever if nothing can stop it.
+async fn stopped(stop: &mut Option<Stop>) {
+    match stop {
+        Some(stop) => stop.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next checkpoint. Never returns without checkpoints.
+async fn tick(checkpoints: &mut Option<tokio::time::Interval>) {
+    match checkpoints {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the playback cursor to move. Never returns without a cursor.
+async fn cursor_moved(cursor: &mut Option<watch::Receiver<u32>>) -> Option<u32> {
+    let Some(rx) = cursor else {
+        return std::future::pending().await;
+    };
+    if rx.changed().await.is_err() {
+        // nobody moves it anymore
+        *cursor = None;
+        return None;
+    }
+    let index = *rx.borrow_and_update();
+    Some(index)
+}
+
+async fn run_peer(
+    addr: SocketAddr,
+    key: PeerKey,
+    shared: Arc<Shared>,
+) -> (SocketAddr, anyhow::Result<()>) {
+    let mut has = Bitfield::new(shared.info.n_pieces() as usize);
+    let result = peer_loop(addr, key, &shared, &mut has).await;
+    shared.connected.lock().unwrap().remove(&addr);
+    let mut scheduler = shared.scheduler.lock().unwrap();
+    scheduler.release_peer(key);
+    scheduler.remove_availability(&has);
+    (addr, result)
+}
+
+/// Downloads from one peer until the connection fails, or until the download
+/// is done and the task is aborted. `has` tracks the pieces the peer has.
+async fn peer_loop(
+    addr: SocketAddr,
+    key: PeerKey,
+    shared: &Shared,
+    has: &mut Bitfield,
+) -> anyhow::Result<()> {
+    let mut peer = Peer::connect(addr.to_string()).await?;
+    peer.handshake(shared.info_hash, shared.peer_id).await?;
+    shared
+        .connected
+        .lock()
+        .unwrap()
+        .insert(addr, FLAG_REACHABLE);
+    let mut have = shared.have.subscribe();
+    let mut told = have.borrow_and_update().clone();
+    peer.send_bitfield(&told).await?;
+    if peer.supports_extensions {
+        // the peer tells us its reqq, and other peers over ut_pex unless
+        // the torrent is private (BEP 27)
+        if !shared.info.is_private() {
+            peer.register_extension(UtPex::new(shared.discovered.clone()));
+        }
+        peer.send_extended_handshake().await?;
+    }
+    peer.set_interested(true).await?;
+
+    let mut pex = PexState::default();
+    let mut pex_timer = tokio::time::interval(PEX_INTERVAL);
+
+    // pieces we may ask for even while choked
+    let mut allowed_fast = Bitfield::new(has.len());
+    let mut pipeline = Pipeline::new(shared.max_requests);
+    let mut blocks_done = shared.blocks_done.subscribe();
+    loop {
+        // blocks that came in from someone else don't need to be sent anymore
+        let cancelled = {
+            let scheduler = shared.scheduler.lock().unwrap();
+            pipeline.cancel(|request| scheduler.block_done(request))
+        };
+        for request in cancelled {
+            peer.send(&Message::Cancel(request)).await?;
+        }
+
+        if let Some(reqq) = peer.remote_extensions.as_ref().and_then(|r| r.reqq) {
+            pipeline.set_peer_limit(reqq);
+        }
+        let allowed;
+        let usable = match peer.peer_choking {
+            false => Some(&*has),
+            true if allowed_fast.count() > 0 => {
+                allowed = has.and(&allowed_fast);
+                Some(&allowed)
+            }
+            true => None,
+        };
+        if let Some(usable) = usable {
+            let mut requests = Vec::new();
+            {
+                let mut scheduler = shared.scheduler.lock().unwrap();
+                if pipeline.is_empty() && scheduler.is_complete() {
+                    return Ok(());
+                }
+                while pipeline.has_room() {
+                    let Some(request) = scheduler.next_block(key, usable) else {
+                        break;
+                    };
+                    pipeline.push(request);
+                    requests.push(request);
+                }
+            }
+            for request in requests {
+                peer.send(&Message::Request(request)).await?;
+            }
+        }
+
+        let msg = tokio::select! {
+            msg = tokio::time::timeout(PEER_TIMEOUT, peer.read_message()) => msg??,
+            // go back up and cancel what we don't need anymore
+            Ok(()) = blocks_done.changed() => continue,
+            Ok(()) = have.changed() => {
+                let have = have.borrow_and_update().clone();
+                for index in have.and_not(&told).ones() {
+                    peer.send(&Message::Have(index as u32)).await?;
+                }
+                told = have;
+                continue;
+            }
+            // the first tick is right away, once the peer said it speaks ut_pex
+            _ = pex_timer.tick(), if !shared.info.is_private() && speaks_pex(&peer) => {
+                let connected: Vec<(SocketAddr, u8)> = shared
+                    .connected
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(other, _)| **other != addr)
+                    .map(|(&other, &flags)| (other, flags))
+                    .collect();
+                if let Some(msg) = pex.update(&connected, Instant::now()) {
+                    peer.send_extension_message("ut_pex", &msg.encode()).await?;
+                }
+                continue;
+            }
+        };
+        if msg.needs_fast_extension() && !peer.supports_fast {
+            anyhow::bail!("sent {:?} without the fast extension", msg);
+        }
+        match msg {
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                let bits = match msg {
+                    Message::Bitfield(bits) => Bitfield::from_bytes(&bits, has.len())?,
+                    Message::HaveAll => Bitfield::full(has.len()),
+                    _ => Bitfield::new(has.len()),
+                };
+                let mut scheduler = shared.scheduler.lock().unwrap();
+                scheduler.remove_availability(has);
+                *has = bits;
+                scheduler.add_availability(has);
+                drop(scheduler);
+                mark_seed(shared, addr, has);
+            }
+            Message::Have(index) => {
+                if index as usize >= has.len() {
+                    anyhow::bail!("have for piece {} of {}", index, has.len());
+                }
+                if !has.get(index as usize) {
+                    has.set(index as usize, true);
+                    shared.scheduler.lock().unwrap().piece_available(index);
+                    mark_seed(shared, addr, has);
+                }
+            }
+            // with the fast extension every request we lose gets rejected on
+            // its own, and allowed fast ones are still answered
+            Message::Choke if !peer.supports_fast => {
+                let mut scheduler = shared.scheduler.lock().unwrap();
+                for request in pipeline.drain() {
+                    scheduler.release(key, &request);
+                }
+            }
+            Message::RejectRequest(request) => {
+                if pipeline
+                    .take(request.piece, request.begin, request.length)
+                    .is_some()
+                {
+                    shared.scheduler.lock().unwrap().release(key, &request);
+                }
+            }
+            Message::AllowedFast(index) => {
+                if (index as usize) < allowed_fast.len() {
+                    allowed_fast.set(index as usize, true);
+                }
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // anything we didn't ask for, or cancelled, is dropped
+                let Some(request) = pipeline.take(index, begin, block.len() as u32) else {
+                    continue;
+                };
+                pipeline.record(block.len(), Instant::now());
+                let piece = {
+                    let mut scheduler = shared.scheduler.lock().unwrap();
+                    scheduler.set_peer_rate(key, pipeline.rate());
+                    if scheduler.is_duplicated(&request) {
+                        shared.blocks_done.send_replace(());
+                    }
+                    scheduler.block_received(key, index, begin, &block)
+                };
+                if let Some(piece) = piece {
+                    if shared.info.verify_piece(index, &piece) {
+                        shared.scheduler.lock().unwrap().piece_verified(index);
+                        shared.pieces.send((index, piece)).await?;
+                    } else {
+                        // only counts against the peer if the whole piece was its own
+                        let blamed = shared.scheduler.lock().unwrap().piece_failed(index);
+                        if blamed == Some(key) {
+                            peer.hash_failures += 1;
+                            if peer.hash_failures >= MAX_HASH_FAILURES {
+                                anyhow::bail!("sent {} corrupt pieces", peer.hash_failures);
+                            }
+                        }
+                    }
+                }
+            }
+            Message::Extended { id, payload } => peer.handle_extended(id, &payload).await?,
+            // `Peer` keeps track of unchoke and interest, and we don't upload
+            // on connections we opened
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::Request(_)
+            | Message::Cancel(_) => {}
+            // the DHT finds its nodes on its own, we don't set the DHT bit,
+            // and the picker knows what it wants better than a suggestion does
+            Message::Port(_) | Message::SuggestPiece(_) => {}
+            Message::KeepAlive | Message::Unknown { .. } => {}
+        }
+    }
+}
+
+fn speaks_pex(peer: &Peer) -> bool {
+    peer.remote_extensions
+        .as_ref()
+        .is_some_and(|remote| remote.m.contains_key("ut_pex"))
+}
+
+/// Keeps the seed flag other peers get over ut_pex up to date.
+fn mark_seed(shared: &Shared, addr: SocketAddr, has: &Bitfield) {
+    if let Some(flags) = shared.connected.lock().unwrap().get_mut(&addr) {
+        match has.is_full() {
+            true => *flags |= FLAG_SEED,
+            false => *flags &= !FLAG_SEED,
+        }
+    }
+}
@@ -1,7 +1,32 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 pub mod tests {
-    use crate::bencode::debencode;
+    use crate::bencode::{
+        debencode, decode, decode_bencoded_value, dict_value_span, BencodeValue, DecodeError,
+    };
+    use crate::bitfield::{Bitfield, BitfieldError};
+    use crate::choker::Choker;
+    use crate::dht::{Dht, DhtState, Krpc, Node, NodeId, Query, Response, RoutingTable, K};
+    use crate::extension::{ExtendedHandshake, ExtensionHandler};
+    use crate::magnet::{Magnet, MagnetError};
+    use crate::message::{Message, MessageError};
+    use crate::metadata::{fetch_metadata, UtMetadata, METADATA_PIECE_SIZE};
+    use crate::peer::{allowed_fast_set, Peer};
+    use crate::pex::{PexMessage, PexState, PEX_INTERVAL};
+    use crate::resume::{resume_path, FileStamp, ResumeData};
+    use crate::scheduler::{BlockRequest, Scheduler};
+    use crate::server::Server;
+    use crate::storage::{FileStorage, MemoryStorage, MmapStorage, Storage};
+    use crate::swarm::{Pipeline, Progress, Swarm, DEFAULT_MAX_REQUESTS};
+    use crate::torrent::{FileInfo, Info, Torrent, TorrentFile, DEFAULT_BLOCK_SIZE};
+    use crate::tracker::{AnnounceResponse, ScrapeResponse, TrackerTiers, UdpTracker};
     use serde_json::json;
+    use sha1::{Digest, Sha1};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
     #[test]
     fn test_parse_int() {
@@ -35,4 +60,2366 @@ pub mod tests {
             json!({"foo":"bar","hello":52})
         );
     }
+
+    fn multi_file_info() -> Info {
+        Info {
+            length: None,
+            files: Some(vec![
+                FileInfo {
+                    length: 5,
+                    path: vec!["a.txt".to_owned()],
+                },
+                FileInfo {
+                    length: 0,
+                    path: vec!["empty".to_owned()],
+                },
+                FileInfo {
+                    length: 7,
+                    path: vec!["sub".to_owned(), "b.txt".to_owned()],
+                },
+            ]),
+            name: "multi".to_owned(),
+            piece_length: 8,
+            pieces: serde_bytes::ByteBuf::from(vec![0; 40]),
+            private: None,
+        }
+    }
+
+    #[test]
+    fn test_multi_file_info_round_trip() {
+        let info = multi_file_info();
+        let bytes = serde_bencode::to_bytes(&info).unwrap();
+        let decoded: Info = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, info);
+        assert_eq!(decoded.total_length(), 12);
+        assert_eq!(decoded.n_pieces(), 2);
+        assert_eq!(decoded.piece_size(0), 8);
+        assert_eq!(decoded.piece_size(1), 4);
+    }
+
+    #[test]
+    fn test_file_layout() {
+        let root = Path::new("out");
+        let layout = multi_file_info().file_layout(root).unwrap();
+        let offsets: Vec<(PathBuf, u64, u64)> = layout
+            .into_iter()
+            .map(|f| (f.path, f.offset, f.length))
+            .collect();
+        assert_eq!(
+            offsets,
+            vec![
+                (root.join("a.txt"), 0, 5),
+                (root.join("empty"), 5, 0),
+                (root.join("sub").join("b.txt"), 5, 7),
+            ]
+        );
+
+        let mut info = multi_file_info();
+        info.files.as_mut().unwrap()[0].path = vec!["..".to_owned(), "evil".to_owned()];
+        assert!(info.file_layout(root).is_err());
+    }
+
+    #[test]
+    fn test_write_files_across_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = multi_file_info().file_layout(dir.path()).unwrap();
+        let mut storage = FileStorage::open(&layout).unwrap();
+        storage.preallocate().unwrap();
+        storage.write_at(0, b"hello, world").unwrap();
+        assert_eq!(storage.read_at(3, 5).unwrap(), b"lo, w");
+
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(dir.path().join("empty")).unwrap(), b"");
+        assert_eq!(
+            std::fs::read(dir.path().join("sub").join("b.txt")).unwrap(),
+            b", world"
+        );
+    }
+
+    #[test]
+    fn test_verify_piece() {
+        let data = b"hello, world";
+        let mut pieces = Vec::new();
+        pieces.extend_from_slice(&Sha1::digest(&data[..8]));
+        pieces.extend_from_slice(&Sha1::digest(&data[8..]));
+        let mut info = multi_file_info();
+        info.pieces = serde_bytes::ByteBuf::from(pieces);
+
+        assert!(info.verify_piece(0, &data[..8]));
+        assert!(info.verify_piece(1, &data[8..]));
+        assert!(!info.verify_piece(1, b"rld!"));
+        assert!(!info.verify_piece(0, &data[..7]));
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(BencodeValue::from(42).encode(), b"i42e");
+        assert_eq!(BencodeValue::from(-7).encode(), b"i-7e");
+        assert_eq!(BencodeValue::from(0).encode(), b"i0e");
+        assert_eq!(BencodeValue::from("spam").encode(), b"4:spam");
+        assert_eq!(BencodeValue::from("").encode(), b"0:");
+        assert_eq!(
+            BencodeValue::from(vec![BencodeValue::from("spam"), BencodeValue::from(42)]).encode(),
+            b"l4:spami42ee"
+        );
+
+        // keys come out sorted no matter the order they went in
+        let dict = BencodeValue::dict([
+            ("foo", BencodeValue::from(42)),
+            ("bar", BencodeValue::from("spam")),
+            ("baz", BencodeValue::List(vec![])),
+        ]);
+        assert_eq!(dict.encode(), b"d3:bar4:spam3:bazle3:fooi42ee");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let inputs: [&[u8]; 6] = [
+            b"i42e",
+            b"4:i42e",
+            b"l4:spami42el5:hello5:worldee",
+            b"d3:bar4:spam3:fooi42ee",
+            b"d4:infod6:lengthi69420e4:name4:spamee",
+            b"d1:ald1:bi-1eeee",
+        ];
+        for input in inputs {
+            let decoded = decode(input).unwrap();
+            assert_eq!(decoded.encode(), input);
+        }
+
+        // the encoder agrees with serde_bencode on a whole torrent
+        let torrent = TorrentFile {
+            announce: Some("http://tracker.example.com/announce".to_owned()),
+            announce_list: Some(vec![
+                vec!["http://tracker.example.com/announce".to_owned()],
+                vec!["udp://a.example.com:80".to_owned()],
+            ]),
+            info: multi_file_info(),
+            info_hash: [0; 20],
+        };
+        let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+        assert_eq!(decode(&bytes).unwrap().encode(), bytes);
+    }
+
+    #[test]
+    fn test_decode_prefix() {
+        let (value, len) = decode_bencoded_value(b"4:spami42e").unwrap();
+        assert_eq!(value, BencodeValue::from("spam"));
+        assert_eq!(len, 6);
+        assert_eq!(decode(b"4:spami42e"), Err(DecodeError::TrailingData(6)));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let cases: [(&[u8], DecodeError); 14] = [
+            (b"", DecodeError::UnexpectedEof(0)),
+            (b"i42", DecodeError::UnexpectedEof(3)),
+            (b"5:spam", DecodeError::UnexpectedEof(6)),
+            (b"l4:spam", DecodeError::UnexpectedEof(7)),
+            (b"d3:foo", DecodeError::UnexpectedEof(6)),
+            (b"ie", DecodeError::InvalidInteger(1)),
+            (b"i-e", DecodeError::InvalidInteger(1)),
+            (b"i4x2e", DecodeError::InvalidInteger(1)),
+            (b"i99999999999999999999e", DecodeError::InvalidInteger(1)),
+            (b"i042e", DecodeError::LeadingZero(1)),
+            (b"i-0e", DecodeError::LeadingZero(1)),
+            (b"l04:spame", DecodeError::LeadingZero(1)),
+            (b"di1ei2ee", DecodeError::NonStringKey(1)),
+            (b"x", DecodeError::UnexpectedByte { byte: b'x', pos: 0 }),
+        ];
+        for (input, err) in cases {
+            assert_eq!(decode(input), Err(err), "{:?}", input);
+        }
+
+        let deep = [vec![b'l'; 1000], vec![b'e'; 1000]].concat();
+        assert!(matches!(decode(&deep), Err(DecodeError::TooDeep(_))));
+    }
+
+    #[test]
+    fn test_info_hash_uses_raw_info_bytes() {
+        // `source` isn't a field of Info but still counts towards the hash
+        let info = b"d6:lengthi12e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:fooe";
+        let torrent = [&b"d8:announce9:localhost4:info"[..], &info[..], &b"e"[..]].concat();
+
+        assert_eq!(
+            dict_value_span(&torrent, b"info").unwrap(),
+            Some(28..28 + info.len())
+        );
+        assert_eq!(dict_value_span(&torrent, b"nope").unwrap(), None);
+
+        let torrent_file = TorrentFile::from_bytes(&torrent).unwrap();
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(torrent_file.info_hash, expected);
+
+        let reencoded: [u8; 20] =
+            Sha1::digest(serde_bencode::to_bytes(&torrent_file.info).unwrap()).into();
+        assert_ne!(torrent_file.info_hash, reencoded);
+    }
+
+    #[test]
+    fn test_torrent_file_checks_pieces() {
+        let torrent = |info: &[u8]| [&b"d4:info"[..], info, &b"e"[..]].concat();
+        let ok = torrent(b"d6:lengthi20e4:name4:spam12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe");
+        assert!(TorrentFile::from_bytes(&ok).is_ok());
+
+        let bad = [
+        // one hash too many
+        torrent(b"d6:lengthi12e4:name4:spam12:piece lengthi16e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe"),
+        // one too few
+        torrent(b"d6:lengthi20e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae"),
+        torrent(b"d6:lengthi12e4:name4:spam12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaae"),
+        torrent(b"d6:lengthi12e4:name4:spam12:piece lengthi16e6:pieces19:aaaaaaaaaaaaaaaaaaae"),
+    ];
+        for bytes in bad {
+            assert!(TorrentFile::from_bytes(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_magnet() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample+file%21\
+         &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+         &tr.1=udp%3A%2F%2Ftracker.example.com%3A1337&x.pe=127.0.0.1:6881",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        assert_eq!(magnet.display_name.as_deref(), Some("sample file!"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://bittorrent-test-tracker.codecrafters.io/announce",
+                "udp://tracker.example.com:1337"
+            ]
+        );
+        assert_eq!(magnet.peers, vec!["127.0.0.1:6881"]);
+
+        // base32 is the same hash
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+
+        assert_eq!(
+            Magnet::parse("http://example.com"),
+            Err(MagnetError::NotAMagnet)
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?dn=nothing"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert!(matches!(
+            Magnet::parse("magnet:?xt=urn:btih:abcd"),
+            Err(MagnetError::InvalidInfoHash(_))
+        ));
+    }
+
+    async fn write_frame(socket: &mut TcpStream, id: u8, payload: &[u8]) {
+        let mut frame = (1 + payload.len() as u32).to_be_bytes().to_vec();
+        frame.push(id);
+        frame.extend_from_slice(payload);
+        socket.write_all(&frame).await.unwrap();
+    }
+
+    async fn read_frame(socket: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut length = [0; 4];
+        socket.read_exact(&mut length).await.ok()?;
+        let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+        socket.read_exact(&mut frame).await.ok()?;
+        Some((frame[0], frame[1..].to_vec()))
+    }
+
+    /// Accepts one connection and answers its handshake with the extension bit set.
+    async fn accept_peer(listener: &TcpListener) -> TcpStream {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+        handshake[25] |= 0x10;
+        handshake[48..].copy_from_slice(b"-FAKE0-0000000000000");
+        socket.write_all(&handshake).await.unwrap();
+        socket
+    }
+
+    /// A peer that only knows how to hand out `metadata` over ut_metadata.
+    async fn serve_metadata(listener: TcpListener, metadata: Vec<u8>) {
+        let mut socket = accept_peer(&listener).await;
+        // something that isn't an extended message, it should be skipped
+        write_frame(&mut socket, 5, &[0xff]).await;
+        let handshake = BencodeValue::dict([
+            (
+                "m",
+                BencodeValue::dict([("ut_metadata", BencodeValue::from(3))]),
+            ),
+            ("metadata_size", BencodeValue::from(metadata.len() as i64)),
+        ]);
+        write_frame(&mut socket, 20, &[&[0], &handshake.encode()[..]].concat()).await;
+
+        let mut their_id = 0;
+        while let Some((id, payload)) = read_frame(&mut socket).await {
+            assert_eq!(id, 20);
+            let msg = decode(&payload[1..]).unwrap();
+            if payload[0] == 0 {
+                their_id = msg
+                    .get("m")
+                    .unwrap()
+                    .get("ut_metadata")
+                    .unwrap()
+                    .as_int()
+                    .unwrap() as u8;
+                continue;
+            }
+            assert_eq!(payload[0], 3);
+            assert_eq!(msg.get("msg_type"), Some(&BencodeValue::from(0)));
+            let piece = msg.get("piece").unwrap().as_int().unwrap() as usize;
+            let data = metadata.chunks(METADATA_PIECE_SIZE).nth(piece).unwrap();
+            let reply = BencodeValue::dict([
+                ("msg_type", BencodeValue::from(1)),
+                ("piece", BencodeValue::from(piece as i64)),
+                ("total_size", BencodeValue::from(metadata.len() as i64)),
+            ]);
+            let body = [&[their_id], &reply.encode()[..], data].concat();
+            write_frame(&mut socket, 20, &body).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata() {
+        // big enough to need two metadata pieces
+        let mut info = multi_file_info();
+        info.pieces = serde_bytes::ByteBuf::from(vec![7; 20 * 1000]);
+        let metadata = serde_bencode::to_bytes(&info).unwrap();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(listener, metadata.clone()));
+
+        let mut peer = Peer::connect(addr.to_string()).await.unwrap();
+        let handshake = peer
+            .handshake(info_hash, *b"00112233445566778899")
+            .await
+            .unwrap();
+        assert!(handshake.supports_extensions());
+        let fetched = fetch_metadata(&mut peer, &info_hash).await.unwrap();
+        assert_eq!(fetched, metadata);
+        assert_eq!(serde_bencode::from_bytes::<Info>(&fetched).unwrap(), info);
+
+        // the same bytes under another hash are refused
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(listener, metadata));
+        let mut peer = Peer::connect(addr.to_string()).await.unwrap();
+        peer.handshake([1; 20], *b"00112233445566778899")
+            .await
+            .unwrap();
+        assert!(fetch_metadata(&mut peer, &[1; 20]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_skips_silent_peer() {
+        let metadata = serde_bencode::to_bytes(&multi_file_info()).unwrap();
+        let magnet = Magnet {
+            info_hash: Sha1::digest(&metadata).into(),
+            display_name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+        };
+
+        // handshakes and then never says another word
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _socket = accept_peer(&silent).await;
+            std::future::pending::<()>().await
+        });
+        let good = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good_addr = good.local_addr().unwrap();
+        tokio::spawn(serve_metadata(good, metadata.clone()));
+
+        let peers: Vec<std::net::SocketAddrV4> = [silent_addr, good_addr]
+            .iter()
+            .map(|addr| addr.to_string().parse().unwrap())
+            .collect();
+        let fetched = tokio::time::timeout(
+            Duration::from_secs(5),
+            Torrent::fetch_metadata_from_any(&peers, &magnet, Duration::from_millis(200)),
+        )
+        .await
+        .expect("stuck on the silent peer");
+        assert_eq!(fetched, Some(metadata));
+    }
+
+    #[test]
+    fn test_extended_handshake() {
+        let mut handshake = ExtendedHandshake {
+            v: Some("jab 0.1.0".to_owned()),
+            p: Some(6881),
+            reqq: Some(250),
+            metadata_size: Some(31235),
+            yourip: Some("10.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+        handshake.m.insert("ut_metadata".to_owned(), 1);
+        handshake.m.insert("ut_pex".to_owned(), 2);
+
+        let bytes = handshake.encode();
+        assert_eq!(
+        bytes,
+        &b"d1:md11:ut_metadatai1e6:ut_pexi2ee13:metadata_sizei31235e1:pi6881e4:reqqi250e1:v9:jab 0.1.06:yourip4:\x0a\x00\x00\x02e"[..]
+    );
+        assert_eq!(ExtendedHandshake::from_bytes(&bytes).unwrap(), handshake);
+
+        // a later handshake can turn extensions off and leaves the rest alone
+        let update = ExtendedHandshake::from_bytes(b"d1:md11:lt_donthavei7e6:ut_pexi0eee").unwrap();
+        handshake.update(update);
+        assert_eq!(handshake.m.get("ut_pex"), None);
+        assert_eq!(handshake.m.get("lt_donthave"), Some(&7));
+        assert_eq!(handshake.reqq, Some(250));
+
+        // junk values are skipped, not fatal
+        let junk =
+            ExtendedHandshake::from_bytes(b"d1:md1:ai300e1:b1:xe1:pi-1e6:yourip3:abce").unwrap();
+        assert_eq!(junk, ExtendedHandshake::default());
+        assert!(ExtendedHandshake::from_bytes(b"i1e").is_err());
+    }
+
+    struct Echo;
+    impl ExtensionHandler for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extension_registry_dispatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let mut socket = accept_peer(&listener).await;
+            let (_, ours) = read_frame(&mut socket).await.unwrap();
+            write_frame(&mut socket, 20, b"\x00d1:md4:echoi9eee").await;
+            // our handshake gave echo id 2, ut_metadata was registered first
+            let handshake = ExtendedHandshake::from_bytes(&ours[1..]).unwrap();
+            assert_eq!(handshake.m.get("echo"), Some(&2));
+            write_frame(&mut socket, 20, b"\x02ping").await;
+            read_frame(&mut socket).await.unwrap()
+        });
+
+        let mut peer = Peer::connect(addr.to_string()).await.unwrap();
+        peer.handshake([0; 20], *b"00112233445566778899")
+            .await
+            .unwrap();
+        peer.register_extension(UtMetadata::fetching(Default::default()));
+        assert_eq!(peer.register_extension(Echo), 2);
+        peer.send_extended_handshake().await.unwrap();
+        for _ in 0..2 {
+            let Message::Extended { id, payload } = peer.read_message().await.unwrap() else {
+                panic!("expected an extended message");
+            };
+            peer.handle_extended(id, &payload).await.unwrap();
+        }
+        // the echo comes back with the id the remote picked
+        assert_eq!(remote.await.unwrap(), (20, b"\x09ping".to_vec()));
+    }
+
+    /// A UDP tracker that knows two peers. It ignores the first announce it gets
+    /// so that the client has to retransmit.
+    async fn udp_stand_in_tracker(socket: UdpSocket) {
+        const CONNECTION_ID: u64 = 0xdead_beef;
+        let mut buf = [0; 2048];
+        let mut dropped_one = false;
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let packet = &buf[..len];
+            let connection_id = u64::from_be_bytes(packet[..8].try_into().unwrap());
+            let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+            let mut reply = Vec::new();
+            match action {
+                0 => {
+                    assert_eq!(connection_id, 0x41727101980);
+                    reply.extend_from_slice(&0u32.to_be_bytes());
+                    reply.extend_from_slice(&packet[12..16]);
+                    reply.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                }
+                1 => {
+                    assert_eq!(len, 98);
+                    assert_eq!(connection_id, CONNECTION_ID);
+                    if !dropped_one {
+                        dropped_one = true;
+                        continue;
+                    }
+                    if packet[16..36] == [0xff; 20] {
+                        reply.extend_from_slice(&3u32.to_be_bytes());
+                        reply.extend_from_slice(&packet[12..16]);
+                        reply.extend_from_slice(b"unknown torrent");
+                    } else {
+                        reply.extend_from_slice(&1u32.to_be_bytes());
+                        reply.extend_from_slice(&packet[12..16]);
+                        for n in [1800u32, 3, 7] {
+                            reply.extend_from_slice(&n.to_be_bytes());
+                        }
+                        reply
+                            .extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                    }
+                }
+                2 => {
+                    assert_eq!(connection_id, CONNECTION_ID);
+                    reply.extend_from_slice(&2u32.to_be_bytes());
+                    reply.extend_from_slice(&packet[12..16]);
+                    for (i, _) in packet[16..].chunks(20).enumerate() {
+                        for n in [i as u32, 100, 5] {
+                            reply.extend_from_slice(&n.to_be_bytes());
+                        }
+                    }
+                }
+                _ => panic!("unexpected action {}", action),
+            }
+            socket.send_to(&reply, from).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_tracker() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        tokio::spawn(udp_stand_in_tracker(socket));
+
+        let mut tracker = UdpTracker::new(&url)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(50), 3);
+        let res = tracker.announce(&[1; 20], 1000).await.unwrap();
+        assert_eq!(
+            res,
+            AnnounceResponse {
+                interval: 1800,
+                leechers: 3,
+                seeders: 7,
+                peers: vec![
+                    "10.0.0.1:6881".parse().unwrap(),
+                    "10.0.0.2:6882".parse().unwrap()
+                ],
+            }
+        );
+
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeResponse {
+                    seeders: 0,
+                    completed: 100,
+                    leechers: 5
+                },
+                ScrapeResponse {
+                    seeders: 1,
+                    completed: 100,
+                    leechers: 5
+                },
+            ]
+        );
+
+        let err = tracker.announce(&[0xff; 20], 1000).await.unwrap_err();
+        assert_eq!(err.to_string(), "tracker error: unknown torrent");
+    }
+
+    #[tokio::test]
+    async fn test_udp_tracker_gives_up() {
+        // nobody answers on this socket
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let mut tracker = UdpTracker::new(&url)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(10), 2);
+        assert!(tracker.announce(&[1; 20], 1000).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_tracker_backs_off_across_connect_and_announce() {
+        // ignores the first connect and every announce, and says what it got
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            let mut connects = 0;
+            loop {
+                let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+                let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+                tx.send(action).unwrap();
+                if action == 0 {
+                    connects += 1;
+                    if connects > 1 {
+                        let reply = [&0u32.to_be_bytes()[..], &buf[12..16], &[1; 8]].concat();
+                        socket.send_to(&reply, from).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let mut tracker = UdpTracker::new(&url)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(10), 2);
+        assert!(tracker.announce(&[1; 20], 1000).await.is_err());
+        // the retry that was used up connecting doesn't come back for the announce
+        let mut actions = Vec::new();
+        while let Ok(action) = rx.try_recv() {
+            actions.push(action);
+        }
+        assert_eq!(actions, [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_tracker_tiers_from_torrent() {
+        let info = b"d6:lengthi12e4:name4:spam12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let with_list = [
+            &b"d8:announce3:one13:announce-listll3:twoel5:three4:fouree4:info"[..],
+            &info[..],
+            &b"e"[..],
+        ]
+        .concat();
+        let torrent_file = TorrentFile::from_bytes(&with_list).unwrap();
+        let tiers = TrackerTiers::from_torrent(&torrent_file);
+        assert_eq!(tiers.tiers().len(), 2);
+        assert_eq!(tiers.tiers()[0], vec!["two"]);
+        let mut second = tiers.tiers()[1].clone();
+        second.sort();
+        assert_eq!(second, vec!["four", "three"]);
+
+        let without_list = [&b"d8:announce3:one4:info"[..], &info[..], &b"e"[..]].concat();
+        let torrent_file = TorrentFile::from_bytes(&without_list).unwrap();
+        assert_eq!(
+            TrackerTiers::from_torrent(&torrent_file).tiers(),
+            &[vec!["one".to_owned()]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracker_tiers_fall_back_and_promote() {
+        let peer: std::net::SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+        let mut tiers = TrackerTiers::new(vec![
+            vec!["dead-1".to_owned()],
+            vec!["dead-2".to_owned(), "alive".to_owned()],
+            vec!["never-asked".to_owned()],
+        ]);
+
+        let mut asked = Vec::new();
+        let peers = tiers
+            .announce_with(|url| {
+                asked.push(url.clone());
+                async move {
+                    match url.as_str() {
+                        "alive" => Ok(vec![peer]),
+                        _ => anyhow::bail!("dead"),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(peers, vec![peer]);
+        assert_eq!(asked[0], "dead-1");
+        assert!(asked.contains(&"alive".to_owned()));
+        assert!(!asked.contains(&"never-asked".to_owned()));
+
+        // the tracker that answered is now first in its tier
+        assert_eq!(tiers.tiers()[1], vec!["alive", "dead-2"]);
+        let mut asked = Vec::new();
+        tiers
+            .announce_with(|url| {
+                asked.push(url.clone());
+                async move {
+                    match url.as_str() {
+                        "alive" => Ok(vec![peer]),
+                        _ => anyhow::bail!("dead"),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(asked, vec!["dead-1", "alive"]);
+
+        let mut all_dead = TrackerTiers::new(vec![vec!["a".to_owned()], vec!["b".to_owned()]]);
+        assert!(all_dead
+            .announce_with(|_| async { anyhow::bail!("dead") })
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tracker_tiers_skip_silent_udp_tracker() {
+        // nobody answers on this socket, and the tracker keeps its slow schedule
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent = format!("udp://{}", socket.local_addr().unwrap());
+        let peer: std::net::SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+        let mut tiers = TrackerTiers::new(vec![vec![silent.clone()], vec!["alive".to_owned()]])
+            .with_timeout(Duration::from_millis(100));
+
+        let peers = tokio::time::timeout(
+            Duration::from_secs(5),
+            tiers.announce_with(|url| async move {
+                match url.as_str() {
+                    "alive" => Ok(vec![peer]),
+                    _ => Ok(UdpTracker::new(&url)
+                        .await?
+                        .announce(&[1; 20], 1000)
+                        .await?
+                        .peers),
+                }
+            }),
+        )
+        .await
+        .expect("stuck on the silent tracker")
+        .unwrap();
+        assert_eq!(peers, vec![peer]);
+        assert_eq!(tiers.tiers()[0], vec![silent]);
+    }
+
+    fn bits(bools: &[bool]) -> Bitfield {
+        Bitfield::from(bools)
+    }
+
+    const TEST_PIECE_LENGTH: u32 = 2 * DEFAULT_BLOCK_SIZE;
+
+    /// Some content that takes up a few pieces, the last one short, and a torrent for it.
+    fn test_torrent(total: usize) -> (Vec<u8>, TorrentFile) {
+        let data: Vec<u8> = (0..total).map(|i| (i * 31 % 251) as u8).collect();
+        let mut pieces = Vec::new();
+        for piece in data.chunks(TEST_PIECE_LENGTH as usize) {
+            pieces.extend_from_slice(&Sha1::digest(piece));
+        }
+        let info = Info {
+            length: Some(total as u64),
+            files: None,
+            name: "data".to_owned(),
+            piece_length: TEST_PIECE_LENGTH,
+            pieces: serde_bytes::ByteBuf::from(pieces),
+            private: None,
+        };
+        let torrent_file = TorrentFile {
+            announce: None,
+            announce_list: None,
+            info,
+            info_hash: [9; 20],
+        };
+        (data, torrent_file)
+    }
+
+    #[derive(Clone, Copy)]
+    enum Seeder {
+        Good,
+        // hangs up after sending this many blocks
+        HangsUpAfter(usize),
+        // stays connected after sending this many blocks, but sends no more
+        StallsAfter(usize),
+        // sends garbage instead of the real data
+        Corrupt,
+        // sits on requests until this many are queued up, then answers them all
+        Batches(usize),
+        // unchokes us but never answers a request
+        Quiet,
+    }
+
+    /// A peer that has all of `data` and answers requests for it.
+    async fn fake_seeder(listener: TcpListener, data: Arc<Vec<u8>>, behaviour: Seeder) {
+        let mut socket = accept_peer(&listener).await;
+        let n_pieces = data.len().div_ceil(TEST_PIECE_LENGTH as usize);
+        let mut bitfield = vec![0u8; n_pieces.div_ceil(8)];
+        for i in 0..n_pieces {
+            bitfield[i / 8] |= 0x80 >> (i % 8);
+        }
+        write_frame(&mut socket, 5, &bitfield).await;
+
+        let mut sent = 0;
+        let mut queued = Vec::new();
+        while let Some((id, payload)) = read_frame(&mut socket).await {
+            match id {
+                // interested
+                2 => write_frame(&mut socket, 1, &[]).await,
+                // request
+                6 => {
+                    if let Seeder::HangsUpAfter(n) = behaviour {
+                        if sent == n {
+                            // give the blocks that are on their way time to get there
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            return;
+                        }
+                    }
+                    if let Seeder::StallsAfter(n) = behaviour {
+                        if sent == n {
+                            continue;
+                        }
+                    }
+                    if let Seeder::Quiet = behaviour {
+                        continue;
+                    }
+                    queued.push(payload);
+                    if let Seeder::Batches(n) = behaviour {
+                        if queued.len() < n {
+                            continue;
+                        }
+                    }
+                    for payload in queued.drain(..) {
+                        let index = u32::from_be_bytes(payload[0..4].try_into().unwrap()) as usize;
+                        let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+                        let length =
+                            u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                        let start = index * TEST_PIECE_LENGTH as usize + begin;
+                        let mut block = data[start..start + length].to_vec();
+                        if let Seeder::Corrupt = behaviour {
+                            block.iter_mut().for_each(|b| *b = !*b);
+                        }
+                        write_frame(&mut socket, 7, &[&payload[0..8], &block[..]].concat()).await;
+                        sent += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn start_seeders(data: &[u8], seeders: &[Seeder]) -> Vec<std::net::SocketAddrV4> {
+        let data = Arc::new(data.to_vec());
+        let mut addrs = Vec::new();
+        for &behaviour in seeders {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            match listener.local_addr().unwrap() {
+                std::net::SocketAddr::V4(addr) => addrs.push(addr),
+                _ => unreachable!(),
+            }
+            tokio::spawn(fake_seeder(listener, data.clone(), behaviour));
+        }
+        addrs
+    }
+
+    #[tokio::test]
+    async fn test_download_from_many_peers() {
+        let (data, torrent_file) = test_torrent(5 * TEST_PIECE_LENGTH as usize + 1000);
+        let addrs = start_seeders(
+            &data,
+            &[
+                Seeder::Corrupt,
+                Seeder::HangsUpAfter(2),
+                Seeder::Good,
+                Seeder::Good,
+            ],
+        )
+        .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data");
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        torrent
+            .download(target.to_str().unwrap().to_owned())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_download_runs_out_of_peers() {
+        let (data, torrent_file) = test_torrent(3 * TEST_PIECE_LENGTH as usize);
+        let addrs = start_seeders(&data, &[Seeder::HangsUpAfter(1), Seeder::Corrupt]).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        let err = torrent
+            .download(dir.path().join("data").to_str().unwrap().to_owned())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("ran out of peers"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_download_pipelines_requests() {
+        // 8 blocks, and the seeder only answers once 4 requests are out
+        let (data, torrent_file) = test_torrent(4 * TEST_PIECE_LENGTH as usize);
+        let addrs = start_seeders(&data, &[Seeder::Batches(4)]).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data");
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            torrent.download(target.to_str().unwrap().to_owned()),
+        )
+        .await
+        .expect("download stalled")
+        .unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+    }
+
+    #[test]
+    fn test_pipeline_window() {
+        let block = |piece, begin| BlockRequest {
+            piece,
+            begin,
+            length: DEFAULT_BLOCK_SIZE,
+        };
+        let mut pipeline = Pipeline::new(DEFAULT_MAX_REQUESTS);
+        assert_eq!(pipeline.window(), 4);
+
+        // 1 MiB/s is worth about 3 seconds / 16 KiB = 192 blocks, capped at the max
+        let start = Instant::now();
+        pipeline.record(1 << 20, start + Duration::from_secs(1));
+        assert_eq!(pipeline.window(), DEFAULT_MAX_REQUESTS);
+        pipeline.set_peer_limit(10);
+        assert_eq!(pipeline.window(), 10);
+
+        pipeline.push(block(0, 0));
+        pipeline.push(block(0, DEFAULT_BLOCK_SIZE));
+        assert!(pipeline.take(0, DEFAULT_BLOCK_SIZE, 100).is_none());
+        assert!(pipeline.take(1, 0, DEFAULT_BLOCK_SIZE).is_none());
+        assert_eq!(
+            pipeline.take(0, DEFAULT_BLOCK_SIZE, DEFAULT_BLOCK_SIZE),
+            Some(block(0, DEFAULT_BLOCK_SIZE))
+        );
+        assert_eq!(pipeline.drain(), vec![block(0, 0)]);
+        assert!(pipeline.is_empty());
+    }
+
+    #[test]
+    fn test_scheduler_finishes_started_pieces_first() {
+        let (data, torrent_file) = test_torrent(3 * TEST_PIECE_LENGTH as usize);
+        let info = &torrent_file.info;
+        let mut scheduler = Scheduler::new(info, 0..3).with_random_first(0);
+        let all = bits(&[true, true, true]);
+        // so rarest first goes 0, 1, 2
+        scheduler.add_availability(&bits(&[false, true, true]));
+        scheduler.add_availability(&bits(&[false, false, true]));
+
+        let first = scheduler.next_block(0, &all).unwrap();
+        assert_eq!((first.piece, first.begin), (0, 0));
+        // another peer gets the other half of the same piece before a new one is started
+        let second = scheduler.next_block(1, &all).unwrap();
+        assert_eq!((second.piece, second.begin), (0, DEFAULT_BLOCK_SIZE));
+        let third = scheduler.next_block(1, &all).unwrap();
+        assert_eq!(third.piece, 1);
+        // peers only get pieces they have
+        assert_eq!(
+            scheduler
+                .next_block(2, &bits(&[false, false, true]))
+                .unwrap()
+                .piece,
+            2
+        );
+        assert_eq!(scheduler.next_block(3, &bits(&[true, false, false])), None);
+
+        // a peer that goes away gives its blocks back
+        scheduler.release_peer(1);
+        let again = scheduler.next_block(3, &all).unwrap();
+        assert_eq!((again.piece, again.begin), (0, DEFAULT_BLOCK_SIZE));
+
+        let block = |req: BlockRequest| {
+            let start = (req.piece * TEST_PIECE_LENGTH + req.begin) as usize;
+            data[start..start + req.length as usize].to_vec()
+        };
+        assert_eq!(scheduler.block_received(0, 0, 0, &block(first)), None);
+        // blocks of the wrong size or that nobody asked for are dropped
+        assert_eq!(
+            scheduler.block_received(3, 0, DEFAULT_BLOCK_SIZE, &[1, 2, 3]),
+            None
+        );
+        assert_eq!(scheduler.block_received(0, 0, 0, &block(first)), None);
+        let piece = scheduler
+            .block_received(3, 0, DEFAULT_BLOCK_SIZE, &block(again))
+            .unwrap();
+        assert!(info.verify_piece(0, &piece));
+        scheduler.piece_verified(0);
+        assert!(!scheduler.is_complete());
+    }
+
+    #[test]
+    fn test_scheduler_keeps_bad_peers_off_a_piece() {
+        let (_, torrent_file) = test_torrent(2 * TEST_PIECE_LENGTH as usize);
+        let mut scheduler = Scheduler::new(&torrent_file.info, [0, 1]).with_random_first(0);
+        let all = bits(&[true, true]);
+        // piece 0 is the rarer one, so it goes first
+        scheduler.add_availability(&bits(&[false, true]));
+        for _ in 0..2 {
+            let request = scheduler.next_block(0, &all).unwrap();
+            scheduler.block_received(0, 0, request.begin, &vec![0; request.length as usize]);
+        }
+        assert_eq!(scheduler.piece_failed(0), Some(0));
+
+        // peer 0 sent a bad piece 0, so it gets piece 1 while there is any of it left
+        assert_eq!(scheduler.next_block(0, &all).unwrap().piece, 1);
+        assert_eq!(scheduler.next_block(0, &all).unwrap().piece, 1);
+        assert_eq!(scheduler.next_block(1, &all).unwrap().piece, 0);
+        // and only gets piece 0 back when there is nothing else
+        assert_eq!(scheduler.next_block(0, &all).unwrap().piece, 0);
+    }
+
+    #[test]
+    fn test_scheduler_blames_nobody_for_a_shared_bad_piece() {
+        let (_, torrent_file) = test_torrent(2 * TEST_PIECE_LENGTH as usize);
+        let mut scheduler = Scheduler::new(&torrent_file.info, [0, 1]).with_random_first(0);
+        let all = bits(&[true, true]);
+        scheduler.add_availability(&bits(&[false, true]));
+        for peer in [0, 1] {
+            let request = scheduler.next_block(peer, &all).unwrap();
+            assert_eq!(request.piece, 0);
+            scheduler.block_received(peer, 0, request.begin, &vec![0; request.length as usize]);
+        }
+        // either of them could have sent the bad block
+        assert_eq!(scheduler.piece_failed(0), None);
+
+        // so both keep away from piece 0 while there is something else
+        assert_eq!(scheduler.next_block(0, &all).unwrap().piece, 1);
+        assert_eq!(scheduler.next_block(1, &all).unwrap().piece, 1);
+        assert_eq!(scheduler.next_block(2, &all).unwrap().piece, 0);
+        // and the next copy of it comes from one peer only
+        assert_eq!(scheduler.next_block(3, &all), None);
+        assert_eq!(scheduler.next_block(2, &all).unwrap().piece, 0);
+    }
+
+    #[tokio::test]
+    async fn test_download_keeps_good_peer_sharing_pieces_with_corrupt_one() {
+        // the good peer is the only one that can finish the download, so it
+        // must not be dropped for blocks the corrupt one sent
+        let (data, torrent_file) = test_torrent(8 * TEST_PIECE_LENGTH as usize);
+        let addrs = start_seeders(&data, &[Seeder::Corrupt, Seeder::Good]).await;
+
+        let mut storage = MemoryStorage::new(data.len() as u64);
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        // one request at a time each, so the two of them split most pieces
+        torrent.max_requests = 1;
+        tokio::time::timeout(Duration::from_secs(10), torrent.download_to(&mut storage))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.into_inner(), data);
+    }
+
+    #[test]
+    fn test_scheduler_picks_rarest_first() {
+        let (_, torrent_file) = test_torrent(4 * TEST_PIECE_LENGTH as usize);
+        let mut scheduler = Scheduler::new(&torrent_file.info, 0..4).with_random_first(0);
+        let all = bits(&[true; 4]);
+        scheduler.add_availability(&all);
+        scheduler.add_availability(&bits(&[true, true, false, true]));
+        scheduler.add_availability(&bits(&[false, false, false, true]));
+        scheduler.piece_available(0);
+
+        // piece 2 has 1 copy, piece 1 has 2
+        assert_eq!(scheduler.next_block(0, &all).unwrap().piece, 2);
+        // a started piece is finished before going for the next rarest
+        assert_eq!(scheduler.next_block(0, &all).unwrap().piece, 2);
+        assert_eq!(scheduler.next_block(0, &all).unwrap().piece, 1);
+
+        // 0 and 3 have 3 copies each, until a peer with 3 leaves
+        scheduler.release_peer(0);
+        scheduler.remove_availability(&bits(&[false, false, false, true]));
+        let has = bits(&[true, false, false, true]);
+        assert_eq!(scheduler.next_block(1, &has).unwrap().piece, 3);
+    }
+
+    #[test]
+    fn test_scheduler_breaks_rarest_ties_at_random() {
+        let (_, torrent_file) = test_torrent(8 * TEST_PIECE_LENGTH as usize);
+        let all = bits(&[true; 8]);
+        let firsts: std::collections::HashSet<u32> = (0..40)
+            .map(|_| {
+                let mut scheduler = Scheduler::new(&torrent_file.info, 0..8).with_random_first(0);
+                scheduler.add_availability(&all);
+                // 0 and 7 are more common, the rest are equally rare
+                scheduler.add_availability(&bits(&[
+                    true, false, false, false, false, false, false, true,
+                ]));
+                scheduler.next_block(0, &all).unwrap().piece
+            })
+            .collect();
+        assert!(!firsts.contains(&0) && !firsts.contains(&7));
+        assert!(firsts.len() > 1, "always picked {:?}", firsts);
+    }
+
+    #[test]
+    fn test_scheduler_starts_with_random_pieces() {
+        let (_, torrent_file) = test_torrent(8 * TEST_PIECE_LENGTH as usize);
+        let all = bits(&[true; 8]);
+        let firsts: std::collections::HashSet<u32> = (0..20)
+            .map(|_| {
+                let mut scheduler = Scheduler::new(&torrent_file.info, 0..8);
+                scheduler.add_availability(&bits(&[
+                    true, false, false, false, false, false, false, false,
+                ]));
+                scheduler.next_block(0, &all).unwrap().piece
+            })
+            .collect();
+        // rarest first would always pick piece 1
+        assert!(firsts.len() > 1, "{:?}", firsts);
+    }
+
+    #[test]
+    fn test_scheduler_streams_from_cursor() {
+        let (_, torrent_file) = test_torrent(20 * TEST_PIECE_LENGTH as usize);
+        let mut scheduler = Scheduler::new(&torrent_file.info, 0..20);
+        let all = bits(&[true; 20]);
+        scheduler.stream_from(3, Duration::from_secs(1), Instant::now());
+
+        // the piece at the cursor is due first, then the ones after it
+        let picked: Vec<u32> = (0..4)
+            .map(|_| scheduler.next_block(0, &all).unwrap().piece)
+            .collect();
+        assert_eq!(picked, [3, 3, 4, 4]);
+
+        // past the window pieces still come in order, then the ones before the cursor
+        scheduler.stream_from(19, Duration::from_secs(1), Instant::now());
+        let picked: Vec<u32> = (0..4)
+            .map(|_| scheduler.next_block(0, &all).unwrap().piece)
+            .collect();
+        assert_eq!(picked, [19, 19, 0, 0]);
+    }
+
+    #[test]
+    fn test_scheduler_reissues_urgent_blocks_to_faster_peers() {
+        let (_, torrent_file) = test_torrent(3 * TEST_PIECE_LENGTH as usize);
+        let mut scheduler = Scheduler::new(&torrent_file.info, 0..3);
+        let all = bits(&[true; 3]);
+        // without piece 2 to go, this would be the endgame
+        let fast = bits(&[true, true, false]);
+        scheduler.stream_from(0, Duration::from_secs(10), Instant::now());
+        scheduler.set_peer_rate(0, 1_000.0);
+        scheduler.set_peer_rate(1, 100_000.0);
+        let slow: Vec<BlockRequest> = (0..4)
+            .map(|_| scheduler.next_block(0, &all).unwrap())
+            .collect();
+
+        // only piece 0 is due soon, so peer 1 takes over its blocks and nothing else
+        assert_eq!(scheduler.next_block(1, &fast), Some(slow[0]));
+        assert_eq!(scheduler.next_block(1, &fast), Some(slow[1]));
+        assert_eq!(scheduler.next_block(1, &fast), None);
+        // and the slow peer doesn't take them back
+        assert_eq!(scheduler.next_block(0, &fast), None);
+        // but it was asked too, so it gets a block back that peer 1 gives up
+        assert!(scheduler.is_duplicated(&slow[1]));
+        scheduler.release(1, &slow[1]);
+        assert!(!scheduler.is_duplicated(&slow[1]));
+        assert_eq!(scheduler.next_block(2, &fast), None);
+
+        // the slow peer giving up its request leaves the block with peer 1
+        scheduler.release(0, &slow[0]);
+        assert_eq!(scheduler.next_block(2, &fast), None);
+        scheduler.release(1, &slow[0]);
+        assert_eq!(scheduler.next_block(2, &fast), Some(slow[0]));
+    }
+
+    #[tokio::test]
+    async fn test_download_sequential() {
+        let (data, torrent_file) = test_torrent(6 * TEST_PIECE_LENGTH as usize);
+        let addrs = start_seeders(&data, &[Seeder::Good]).await;
+
+        let (_cursor, rx) = tokio::sync::watch::channel(0);
+        let mut order = Vec::new();
+        Swarm::new(
+            torrent_file.info.clone(),
+            torrent_file.info_hash,
+            *b"00112233445566778899",
+        )
+        .with_cursor(rx, Duration::from_secs(1))
+        .download(addrs, 0..6, |progress| {
+            if let Progress::Piece(index, piece) = progress {
+                assert!(torrent_file.info.verify_piece(index, &piece));
+                order.push(index);
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(order, [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_scheduler_endgame() {
+        let (data, torrent_file) = test_torrent(TEST_PIECE_LENGTH as usize);
+        let mut scheduler = Scheduler::new(&torrent_file.info, [0]);
+        let all = bits(&[true]);
+        let first = scheduler.next_block(0, &all).unwrap();
+        assert!(!scheduler.in_endgame());
+        let second = scheduler.next_block(0, &all).unwrap();
+        assert!(scheduler.in_endgame());
+
+        // everything is requested, so other peers get the same blocks, each once
+        assert_eq!(scheduler.next_block(0, &all), None);
+        assert_eq!(scheduler.next_block(1, &all), Some(first));
+        assert_eq!(scheduler.next_block(1, &all), Some(second));
+        assert_eq!(scheduler.next_block(1, &all), None);
+        assert_eq!(scheduler.next_block(2, &all), Some(first));
+        assert!(scheduler.is_duplicated(&first));
+
+        // the first copy is kept, the rest are for cancelling
+        let block = |req: BlockRequest| {
+            data[req.begin as usize..(req.begin + req.length) as usize].to_vec()
+        };
+        assert_eq!(
+            scheduler.block_received(0, 0, first.begin, &block(first)),
+            None
+        );
+        assert!(scheduler.block_done(&first));
+        assert!(!scheduler.is_duplicated(&first));
+        let mut pipeline = Pipeline::new(DEFAULT_MAX_REQUESTS);
+        pipeline.push(first);
+        pipeline.push(second);
+        assert_eq!(pipeline.cancel(|r| scheduler.block_done(r)), vec![first]);
+        assert_eq!(scheduler.block_received(1, 0, first.begin, &[0; 3]), None);
+
+        // a peer leaving hands its block to the one that was asked for it too
+        scheduler.release_peer(0);
+        assert!(!scheduler.is_duplicated(&second));
+        assert_eq!(scheduler.next_block(3, &all), Some(second));
+        let piece = scheduler
+            .block_received(3, 0, second.begin, &block(second))
+            .unwrap();
+        assert_eq!(piece, data);
+    }
+
+    #[tokio::test]
+    async fn test_download_endgame_works_around_a_stalled_peer() {
+        let (data, torrent_file) = test_torrent(4 * TEST_PIECE_LENGTH as usize);
+        let addrs = start_seeders(&data, &[Seeder::Quiet, Seeder::Good]).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data");
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        // without the endgame this waits for the quiet peer to time out
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            torrent.download(target.to_str().unwrap().to_owned()),
+        )
+        .await
+        .expect("download stalled")
+        .unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_from_disk() {
+        let (data, torrent_file) = test_torrent(4 * TEST_PIECE_LENGTH as usize);
+        let piece = TEST_PIECE_LENGTH as usize;
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data");
+        // pieces 0 and 2 made it to disk last time, 1 is garbage and 3 is cut off
+        let mut partial = data[..3 * piece + 100].to_vec();
+        partial[piece + 5] ^= 0xff;
+        std::fs::write(&target, &partial).unwrap();
+
+        let layout = torrent_file.info.file_layout(&target).unwrap();
+        let mut storage = FileStorage::open(&layout).unwrap();
+        storage.preallocate().unwrap();
+        assert_eq!(std::fs::metadata(&target).unwrap().len(), data.len() as u64);
+        assert_eq!(
+            storage.check_pieces(&torrent_file.info).unwrap(),
+            [true, false, true, false]
+        );
+
+        // 2 pieces are 4 blocks, the seeder won't give out more than that
+        let addrs = start_seeders(&data, &[Seeder::HangsUpAfter(4)]).await;
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        torrent
+            .download(target.to_str().unwrap().to_owned())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+
+        // and with everything there, nobody needs to be asked
+        torrent.extra_peers = Vec::new();
+        torrent
+            .download(target.to_str().unwrap().to_owned())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_resume_data_round_trip() {
+        let resume = ResumeData {
+            info_hash: [7; 20],
+            have: vec![true, false, false, true, true, false, true, false, true],
+            files: vec![
+                FileStamp {
+                    length: 12,
+                    mtime: 1_700_000_000_123_456_789,
+                },
+                FileStamp {
+                    length: 0,
+                    mtime: 0,
+                },
+            ],
+            partial: vec![(
+                1,
+                vec![true, false, true, false, false, false, false, false],
+            )],
+        };
+        assert_eq!(ResumeData::decode(&resume.encode()).unwrap(), resume);
+        assert!(ResumeData::decode(b"d4:infoi1ee").is_err());
+    }
+
+    #[test]
+    fn test_resume_data_notices_changed_files() {
+        let (data, torrent_file) = test_torrent(2 * TEST_PIECE_LENGTH as usize);
+        let info = &torrent_file.info;
+        let dir = tempfile::tempdir().unwrap();
+        let layout = info.file_layout(&dir.path().join("data")).unwrap();
+        let mut storage = FileStorage::open(&layout).unwrap();
+        storage.preallocate().unwrap();
+        storage.write_at(0, &data).unwrap();
+
+        let resume = ResumeData::new(
+            torrent_file.info_hash,
+            vec![true, true],
+            &layout,
+            Vec::new(),
+        );
+        assert!(resume.matches(&torrent_file.info_hash, 2, &layout));
+        assert!(!resume.matches(&[0; 20], 2, &layout));
+        assert!(!resume.matches(&torrent_file.info_hash, 3, &layout));
+
+        std::thread::sleep(Duration::from_millis(10));
+        storage.write_at(0, &data[..10]).unwrap();
+        assert!(!resume.matches(&torrent_file.info_hash, 2, &layout));
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_partial_pieces() {
+        // 4 pieces, 8 blocks
+        let (data, torrent_file) = test_torrent(4 * TEST_PIECE_LENGTH as usize);
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data").to_str().unwrap().to_owned();
+
+        // a whole piece and one block of the next one come in before the peer leaves
+        let addrs = start_seeders(&data, &[Seeder::HangsUpAfter(3)]).await;
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        assert!(torrent.download(target.clone()).await.is_err());
+        let resume = ResumeData::load(&resume_path(&target)).unwrap();
+        assert_eq!(resume.have.iter().filter(|&&have| have).count(), 1);
+        assert_eq!(resume.partial.len(), 1);
+
+        // so the rest is 5 blocks
+        torrent.extra_peers = start_seeders(&data, &[Seeder::HangsUpAfter(5)]).await;
+        torrent.download(target.clone()).await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+        let resume = ResumeData::load(&resume_path(&target)).unwrap();
+        assert!(resume.have.iter().all(|&have| have));
+        assert!(resume.partial.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_download_resumes_without_rehash() {
+        let (data, torrent_file) = test_torrent(4 * TEST_PIECE_LENGTH as usize);
+        let info_hash = torrent_file.info_hash;
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data").to_str().unwrap().to_owned();
+        let layout = torrent_file.info.file_layout(Path::new(&target)).unwrap();
+
+        // 2 pieces come in, then the download hangs until it is interrupted
+        let addrs = start_seeders(&data, &[Seeder::StallsAfter(4)]).await;
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        let stop = tokio::time::sleep(Duration::from_millis(300));
+        assert!(torrent.download_until(target.clone(), stop).await.is_err());
+
+        // the resume file is newer than the pieces written, so it still counts
+        let resume = ResumeData::load(&resume_path(&target)).unwrap();
+        assert!(resume.matches(&info_hash, 4, &layout));
+        assert_eq!(resume.have.iter().filter(|&&have| have).count(), 2);
+
+        // a piece that was on disk but not in the resume file would be fetched
+        // again, and this seeder only has 2 pieces worth of blocks to give
+        torrent.extra_peers = start_seeders(&data, &[Seeder::HangsUpAfter(4)]).await;
+        torrent.download(target.clone()).await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+    }
+
+    #[test]
+    fn test_storage_backends() {
+        let (data, torrent_file) = test_torrent(2 * TEST_PIECE_LENGTH as usize + 100);
+        let mut info = torrent_file.info;
+        // split it over a few files, one of them empty
+        let length = data.len() as u64;
+        info.length = None;
+        info.files = Some(vec![
+            FileInfo {
+                length: 1000,
+                path: vec!["a".to_owned()],
+            },
+            FileInfo {
+                length: 0,
+                path: vec!["empty".to_owned()],
+            },
+            FileInfo {
+                length: length - 1000,
+                path: vec!["sub".to_owned(), "b".to_owned()],
+            },
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let files = info.file_layout(&dir.path().join("files")).unwrap();
+        let mapped = info.file_layout(&dir.path().join("mapped")).unwrap();
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(FileStorage::open(&files).unwrap()),
+            Box::new(MmapStorage::open(&mapped).unwrap()),
+            Box::new(MemoryStorage::new(length)),
+        ];
+        for mut storage in backends {
+            storage.preallocate().unwrap();
+            assert_eq!(storage.check_pieces(&info).unwrap(), [false, false, false]);
+            // the first piece spans both files
+            storage
+                .write_block(&info, 0, 0, &data[..DEFAULT_BLOCK_SIZE as usize])
+                .unwrap();
+            assert!(!storage.verify(&info, 0).unwrap());
+            storage
+                .write_block(
+                    &info,
+                    0,
+                    DEFAULT_BLOCK_SIZE,
+                    &data[DEFAULT_BLOCK_SIZE as usize..TEST_PIECE_LENGTH as usize],
+                )
+                .unwrap();
+            let last = 2 * TEST_PIECE_LENGTH as usize;
+            storage.write_block(&info, 2, 0, &data[last..]).unwrap();
+            storage.flush().unwrap();
+            assert_eq!(storage.check_pieces(&info).unwrap(), [true, false, true]);
+            assert_eq!(
+                storage.read_block(&info, 0, 990, 20).unwrap(),
+                &data[990..1010]
+            );
+        }
+        assert_eq!(
+            std::fs::read(dir.path().join("mapped").join("a")).unwrap(),
+            &data[..1000]
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("files").join("a")).unwrap(),
+            &data[..1000]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_to_memory() {
+        let (data, torrent_file) = test_torrent(3 * TEST_PIECE_LENGTH as usize + 10);
+        let addrs = start_seeders(&data, &[Seeder::Good]).await;
+        let mut storage = MemoryStorage::new(data.len() as u64);
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        torrent.download_to(&mut storage).await.unwrap();
+        assert_eq!(storage.into_inner(), data);
+    }
+
+    /// Starts a `Server` on a free port, holding the pieces of `data` in `have`.
+    async fn start_server(
+        data: &[u8],
+        torrent_file: &TorrentFile,
+        have: Vec<bool>,
+    ) -> std::net::SocketAddrV4 {
+        let mut storage = MemoryStorage::new(data.len() as u64);
+        storage.write_at(0, data).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let server = Server::new(*b"-JAB000-000000000000").with_torrent(
+            torrent_file.info.clone(),
+            torrent_file.info_hash,
+            bits(&have),
+            Box::new(storage),
+        );
+        tokio::spawn(server.serve(listener));
+        addr
+    }
+
+    async fn connect_to_server(
+        addr: std::net::SocketAddrV4,
+        info_hash: [u8; 20],
+        reserved: [u8; 8],
+    ) -> TcpStream {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut handshake = vec![19];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&reserved);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(b"-FAKE0-0000000000000");
+        socket.write_all(&handshake).await.unwrap();
+        socket
+    }
+
+    fn request_payload(piece: u32, begin: u32, length: u32) -> Vec<u8> {
+        [piece, begin, length]
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_server_answers_requests() {
+        let (data, torrent_file) = test_torrent(2 * TEST_PIECE_LENGTH as usize + 100);
+        let addr = start_server(&data, &torrent_file, vec![true, false, true]).await;
+        let mut socket = connect_to_server(addr, torrent_file.info_hash, [0; 8]).await;
+
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(&handshake[28..48], &torrent_file.info_hash);
+        assert_eq!(&handshake[48..], b"-JAB000-000000000000");
+        assert_eq!(read_frame(&mut socket).await, Some((5, vec![0b1010_0000])));
+
+        // nothing is sent back until we're unchoked
+        write_frame(&mut socket, 6, &request_payload(0, 0, 100)).await;
+        write_frame(&mut socket, 2, &[]).await;
+        assert_eq!(read_frame(&mut socket).await, Some((1, vec![])));
+
+        // a piece we don't have is skipped, the cancelled one never arrives
+        write_frame(&mut socket, 6, &request_payload(1, 0, 100)).await;
+        write_frame(&mut socket, 6, &request_payload(2, 0, 100)).await;
+        write_frame(&mut socket, 6, &request_payload(0, 16, 32)).await;
+        let mut expected = 2u32.to_be_bytes().to_vec();
+        expected.extend_from_slice(&0u32.to_be_bytes());
+        expected.extend_from_slice(&data[2 * TEST_PIECE_LENGTH as usize..]);
+        assert_eq!(read_frame(&mut socket).await, Some((7, expected)));
+        let mut expected = 0u32.to_be_bytes().to_vec();
+        expected.extend_from_slice(&16u32.to_be_bytes());
+        expected.extend_from_slice(&data[16..48]);
+        assert_eq!(read_frame(&mut socket).await, Some((7, expected)));
+
+        // asking for more than the piece has gets us dropped
+        write_frame(&mut socket, 6, &request_payload(2, 0, 101)).await;
+        assert_eq!(read_frame(&mut socket).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_server_drops_unknown_torrents() {
+        let (data, torrent_file) = test_torrent(TEST_PIECE_LENGTH as usize);
+        let addr = start_server(&data, &torrent_file, vec![true]).await;
+        let mut socket = connect_to_server(addr, [1; 20], [0; 8]).await;
+        let mut handshake = [0; 68];
+        assert!(socket.read_exact(&mut handshake).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_from_server() {
+        let (data, torrent_file) = test_torrent(5 * TEST_PIECE_LENGTH as usize + 1000);
+        let addr = start_server(&data, &torrent_file, vec![true; 6]).await;
+        let mut storage = MemoryStorage::new(data.len() as u64);
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = vec![addr];
+        torrent.download_to(&mut storage).await.unwrap();
+        assert_eq!(storage.into_inner(), data);
+    }
+
+    #[test]
+    fn test_choker_unchokes_the_best_peers() {
+        let mut choker = Choker::new(2);
+        let peers: Vec<_> = (0..5).map(|_| choker.add_peer()).collect();
+        // the first two get a free slot, then one for the optimistic unchoke
+        assert!(choker.set_interested(peers[0], true));
+        assert!(choker.set_interested(peers[1], true));
+        assert!(choker.set_interested(peers[2], true));
+        assert!(!choker.set_interested(peers[3], true));
+        assert!(!choker.is_unchoked(peers[3]));
+
+        // peer 4 isn't interested, so uploading the most doesn't help it
+        choker.uploaded(peers[4], 1000);
+        choker.uploaded(peers[3], 500);
+        choker.uploaded(peers[2], 200);
+        let now = Instant::now();
+        choker.rechoke(now);
+        assert!(choker.is_unchoked(peers[3]) && choker.is_unchoked(peers[2]));
+        assert!(!choker.is_unchoked(peers[4]));
+        let optimistic = if choker.is_unchoked(peers[0]) {
+            peers[0]
+        } else {
+            peers[1]
+        };
+        assert!(choker.is_unchoked(optimistic));
+        assert_eq!((0..5).filter(|&p| choker.is_unchoked(p)).count(), 3);
+
+        // the ones that take the most win, the optimistic unchoke stays put
+        // until its time is up
+        choker.uploaded(peers[0], 300);
+        choker.uploaded(peers[1], 400);
+        choker.rechoke(now + Duration::from_secs(10));
+        assert!(choker.is_unchoked(peers[0]) && choker.is_unchoked(peers[1]));
+        let optimistic = if choker.is_unchoked(peers[2]) {
+            peers[2]
+        } else {
+            peers[3]
+        };
+        assert_eq!((0..5).filter(|&p| choker.is_unchoked(p)).count(), 3);
+
+        choker.uploaded(peers[0], 300);
+        choker.uploaded(peers[1], 400);
+        choker.set_interested(optimistic, false);
+        choker.rechoke(now + Duration::from_secs(20));
+        assert!(!choker.is_unchoked(optimistic));
+        assert_eq!((0..5).filter(|&p| choker.is_unchoked(p)).count(), 3);
+
+        choker.remove_peer(peers[0]);
+        assert!(!choker.is_unchoked(peers[0]));
+    }
+
+    #[test]
+    fn test_choker_rotates_the_optimistic_unchoke() {
+        let mut choker = Choker::new(1);
+        let peers: Vec<_> = (0..10).map(|_| choker.add_peer()).collect();
+        for &peer in &peers {
+            choker.set_interested(peer, true);
+        }
+        let start = Instant::now();
+        let mut optimistic = Vec::new();
+        for round in 0..30 {
+            choker.uploaded(peers[0], 1000);
+            choker.rechoke(start + Duration::from_secs(10 * round));
+            assert!(choker.is_unchoked(peers[0]));
+            let others: Vec<_> = peers[1..]
+                .iter()
+                .copied()
+                .filter(|&p| choker.is_unchoked(p))
+                .collect();
+            assert_eq!(others.len(), 1);
+            optimistic.push(others[0]);
+        }
+        // it only moves every 30 seconds, and gets around
+        for rounds in optimistic.chunks(3) {
+            assert!(rounds.iter().all(|&p| p == rounds[0]));
+        }
+        optimistic.dedup();
+        assert!(optimistic.len() > 1);
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let request = BlockRequest {
+            piece: 1,
+            begin: 0x4000,
+            length: 0x4000,
+        };
+        let cases: [(Message, &[u8]); 19] = [
+            (Message::KeepAlive, b"\0\0\0\0"),
+            (Message::Choke, b"\0\0\0\x01\0"),
+            (Message::Unchoke, b"\0\0\0\x01\x01"),
+            (Message::Interested, b"\0\0\0\x01\x02"),
+            (Message::NotInterested, b"\0\0\0\x01\x03"),
+            (Message::Have(258), b"\0\0\0\x05\x04\0\0\x01\x02"),
+            (Message::Bitfield(vec![0xa0]), b"\0\0\0\x02\x05\xa0"),
+            (
+                Message::Request(request),
+                b"\0\0\0\x0d\x06\0\0\0\x01\0\0\x40\0\0\0\x40\0",
+            ),
+            (
+                Message::Piece {
+                    index: 1,
+                    begin: 2,
+                    block: b"hi".to_vec(),
+                },
+                b"\0\0\0\x0b\x07\0\0\0\x01\0\0\0\x02hi",
+            ),
+            (
+                Message::Cancel(request),
+                b"\0\0\0\x0d\x08\0\0\0\x01\0\0\x40\0\0\0\x40\0",
+            ),
+            (Message::Port(6881), b"\0\0\0\x03\x09\x1a\xe1"),
+            (
+                Message::Extended {
+                    id: 0,
+                    payload: b"de".to_vec(),
+                },
+                b"\0\0\0\x04\x14\0de",
+            ),
+            // an empty bitfield is fine, the peer may just have nothing
+            (Message::Bitfield(vec![]), b"\0\0\0\x01\x05"),
+            (Message::SuggestPiece(3), b"\0\0\0\x05\x0d\0\0\0\x03"),
+            (Message::HaveAll, b"\0\0\0\x01\x0e"),
+            (Message::HaveNone, b"\0\0\0\x01\x0f"),
+            (
+                Message::RejectRequest(request),
+                b"\0\0\0\x0d\x10\0\0\0\x01\0\0\x40\0\0\0\x40\0",
+            ),
+            (Message::AllowedFast(7), b"\0\0\0\x05\x11\0\0\0\x07"),
+            (
+                Message::Unknown {
+                    id: 42,
+                    payload: b"x".to_vec(),
+                },
+                b"\0\0\0\x02\x2ax",
+            ),
+        ];
+        for (msg, bytes) in cases {
+            assert_eq!(msg.encode(), bytes, "{:?}", msg);
+            assert_eq!(Message::decode(&bytes[4..]), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn test_message_rejects_bad_lengths() {
+        let cases: [&[u8]; 10] = [
+            b"\0\0",
+            b"\x0e\0",
+            b"\x11\0\0\0",
+            b"\x02x",
+            b"\x04\0\0\x01",
+            b"\x06\0\0\0\x01\0\0\0\0\0\0\x40",
+            b"\x07\0\0\0\x01\0\0\0",
+            b"\x08",
+            b"\x09\x1a\xe1\0",
+            b"\x14",
+        ];
+        for frame in cases {
+            assert_eq!(
+                Message::decode(frame),
+                Err(MessageError::BadLength {
+                    id: frame[0],
+                    length: frame.len() - 1
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_message_keeps_every_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let mut socket = accept_peer(&listener).await;
+            // a frame split over two writes, and a few at once
+            socket.write_all(b"\0\0\0\x03\x09").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            socket.write_all(b"\x1a\xe1").await.unwrap();
+            socket
+                .write_all(b"\0\0\0\0\0\0\0\x02\x2ax\0\0\0\x01\x01")
+                .await
+                .unwrap();
+            socket.write_all(&[0xff; 4]).await.unwrap();
+            socket
+        });
+
+        let mut peer = Peer::connect(addr.to_string()).await.unwrap();
+        peer.handshake([0; 20], *b"00112233445566778899")
+            .await
+            .unwrap();
+        assert_eq!(peer.read_message().await.unwrap(), Message::Port(6881));
+        assert_eq!(peer.read_message().await.unwrap(), Message::KeepAlive);
+        assert_eq!(
+            peer.read_message().await.unwrap(),
+            Message::Unknown {
+                id: 42,
+                payload: b"x".to_vec()
+            }
+        );
+        assert!(peer.peer_choking);
+        assert_eq!(peer.read_message().await.unwrap(), Message::Unchoke);
+        assert!(!peer.peer_choking);
+        let err = peer.read_message().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MessageError>(),
+            Some(&MessageError::TooLong(u32::MAX))
+        );
+        drop(remote.await.unwrap());
+    }
+
+    #[test]
+    fn test_bitfield() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), [0, 0]);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+        bitfield.set(3, true);
+        bitfield.set(3, false);
+        assert_eq!(bitfield.as_bytes(), [0x80, 0x40]);
+        assert!(bitfield.get(0) && bitfield.get(9) && !bitfield.get(3));
+        assert!(!bitfield.get(10));
+        assert_eq!(bitfield.count(), 2);
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), [0, 9]);
+        assert_eq!(
+            bitfield.missing().collect::<Vec<_>>(),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert!(!bitfield.is_full());
+
+        let theirs = bits(&[
+            true, true, false, true, false, false, false, false, false, false,
+        ]);
+        assert_eq!(theirs.and_not(&bitfield).ones().collect::<Vec<_>>(), [1, 3]);
+        assert!(bits(&[true; 3]).is_full());
+
+        assert_eq!(Bitfield::from_bytes(&[0x80, 0x40], 10), Ok(bitfield));
+        assert_eq!(
+            Bitfield::from_bytes(&[0x80], 10),
+            Err(BitfieldError::WrongLength { got: 1, len: 10 })
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0x80, 0x40, 0], 10),
+            Err(BitfieldError::WrongLength { got: 3, len: 10 })
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0x80, 0x20], 10),
+            Err(BitfieldError::SpareBits)
+        );
+        assert!(Bitfield::from_bytes(&[0xff], 8).unwrap().is_full());
+    }
+
+    #[tokio::test]
+    async fn test_swarm_announces_new_pieces() {
+        let (data, torrent_file) = test_torrent(4 * TEST_PIECE_LENGTH as usize);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let served = data.clone();
+        // holds back piece 3 until it has heard about two others
+        let remote = tokio::spawn(async move {
+            let mut socket = accept_peer(&listener).await;
+            write_frame(&mut socket, 5, &[0xf0]).await;
+            write_frame(&mut socket, 1, &[]).await;
+            let mut bitfield = None;
+            let mut haves = Vec::new();
+            let mut held = Vec::new();
+            while let Some((id, payload)) = read_frame(&mut socket).await {
+                let int = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+                match id {
+                    5 => bitfield = Some(payload.clone()),
+                    4 => haves.push(int(0)),
+                    6 => held.push(payload.clone()),
+                    _ => {}
+                }
+                while let Some(i) = held
+                    .iter()
+                    .position(|r| r[..4] != [0, 0, 0, 3] || haves.len() >= 2)
+                {
+                    let request = held.remove(i);
+                    let field =
+                        |i: usize| u32::from_be_bytes(request[i..i + 4].try_into().unwrap());
+                    let start = (field(0) * TEST_PIECE_LENGTH + field(4)) as usize;
+                    let mut piece = request[..8].to_vec();
+                    piece.extend_from_slice(&served[start..start + field(8) as usize]);
+                    write_frame(&mut socket, 7, &piece).await;
+                }
+            }
+            (bitfield, haves)
+        });
+
+        let mut stored = Vec::new();
+        Swarm::new(
+            torrent_file.info.clone(),
+            torrent_file.info_hash,
+            *b"00112233445566778899",
+        )
+        .with_have(bits(&[true, false, false, false]))
+        .download(vec![addr], 1..4, |progress| {
+            if let Progress::Piece(index, _) = progress {
+                stored.push(index);
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(stored.last(), Some(&3));
+
+        // the peer heard about what we had to begin with, then about each new piece
+        let (bitfield, mut haves) = remote.await.unwrap();
+        assert_eq!(bitfield, Some(vec![0x80]));
+        haves.truncate(2);
+        haves.sort();
+        assert_eq!(haves, [1, 2]);
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        // the example from BEP 6
+        let ip = "80.4.4.200".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // the last byte of the address doesn't matter
+        assert_eq!(
+            allowed_fast_set("80.4.4.1".parse().unwrap(), &[0xaa; 20], 1313, 7),
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7)
+        );
+        // can't allow more pieces than there are
+        let mut all = allowed_fast_set(ip, &[0xaa; 20], 3, 10);
+        all.sort();
+        assert_eq!(all, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_server_fast_extension() {
+        let (data, torrent_file) = test_torrent(12 * TEST_PIECE_LENGTH as usize);
+        let addr = start_server(&data, &torrent_file, vec![true; 12]).await;
+        let mut reserved = [0; 8];
+        reserved[7] |= 0x04;
+        let mut socket = connect_to_server(addr, torrent_file.info_hash, reserved).await;
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+        assert_ne!(handshake[27] & 0x04, 0);
+
+        // a full bitfield is sent as have all, then the pieces we may ask for
+        // while choked
+        assert_eq!(read_frame(&mut socket).await, Some((14, vec![])));
+        let mut allowed = Vec::new();
+        for _ in 0..10 {
+            let (id, payload) = read_frame(&mut socket).await.unwrap();
+            assert_eq!(id, 17);
+            allowed.push(u32::from_be_bytes(payload.try_into().unwrap()));
+        }
+        let expected = allowed_fast_set(
+            "127.0.0.1".parse().unwrap(),
+            &torrent_file.info_hash,
+            12,
+            10,
+        );
+        assert_eq!(allowed, expected);
+
+        // while choked, other pieces are rejected and allowed ones are served
+        let other = (0..12).find(|i| !allowed.contains(i)).unwrap();
+        write_frame(&mut socket, 6, &request_payload(other, 0, 100)).await;
+        assert_eq!(
+            read_frame(&mut socket).await,
+            Some((16, request_payload(other, 0, 100)))
+        );
+        write_frame(&mut socket, 6, &request_payload(allowed[0], 0, 100)).await;
+        let start = (allowed[0] * TEST_PIECE_LENGTH) as usize;
+        let mut expected = request_payload(allowed[0], 0, 100)[..8].to_vec();
+        expected.extend_from_slice(&data[start..start + 100]);
+        assert_eq!(read_frame(&mut socket).await, Some((7, expected)));
+    }
+
+    #[tokio::test]
+    async fn test_download_while_choked_with_allowed_fast() {
+        let (data, torrent_file) = test_torrent(3 * TEST_PIECE_LENGTH as usize);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let served = data.clone();
+        // never unchokes us, but lets us have every piece anyway
+        tokio::spawn(async move {
+            let mut socket = accept_peer(&listener).await;
+            write_frame(&mut socket, 14, &[]).await;
+            for index in 0..3u32 {
+                write_frame(&mut socket, 17, &index.to_be_bytes()).await;
+            }
+            while let Some((id, payload)) = read_frame(&mut socket).await {
+                if id != 6 {
+                    continue;
+                }
+                let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+                let start = (field(0) * TEST_PIECE_LENGTH + field(4)) as usize;
+                let mut piece = payload[..8].to_vec();
+                piece.extend_from_slice(&served[start..start + field(8) as usize]);
+                write_frame(&mut socket, 7, &piece).await;
+            }
+        });
+
+        let mut storage = MemoryStorage::new(data.len() as u64);
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = vec![addr];
+        tokio::time::timeout(Duration::from_secs(10), torrent.download_to(&mut storage))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.into_inner(), data);
+    }
+
+    #[test]
+    fn test_pex_message_round_trip() {
+        let msg = PexMessage {
+            added: vec![
+                ("10.0.0.1:6881".parse().unwrap(), 0x10),
+                ("[2001:db8::1]:51413".parse().unwrap(), 0x02),
+                ("10.0.0.2:80".parse().unwrap(), 0),
+            ],
+            dropped: vec![
+                "10.0.0.3:6881".parse().unwrap(),
+                "[2001:db8::2]:6881".parse().unwrap(),
+            ],
+        };
+        let decoded = PexMessage::from_bytes(&msg.encode()).unwrap();
+        assert_eq!(decoded.added.len(), 3);
+        for added in &msg.added {
+            assert!(decoded.added.contains(added), "{:?}", added);
+        }
+        assert_eq!(decoded.dropped, msg.dropped);
+
+        // flags are optional, and everything else is too
+        let bare =
+            BencodeValue::dict([("added", BencodeValue::from(&[10, 0, 0, 1, 0x1a, 0xe1][..]))]);
+        assert_eq!(
+            PexMessage::from_bytes(&bare.encode()).unwrap(),
+            PexMessage {
+                added: vec![("10.0.0.1:6881".parse().unwrap(), 0)],
+                dropped: vec![],
+            }
+        );
+        let short = BencodeValue::dict([("dropped", BencodeValue::from(&[10, 0, 0, 1, 0x1a][..]))]);
+        assert!(PexMessage::from_bytes(&short.encode()).is_err());
+    }
+
+    #[test]
+    fn test_pex_state_limits_messages() {
+        let peers: Vec<(std::net::SocketAddr, u8)> = (0..60)
+            .map(|i| (format!("10.0.0.{}:6881", i).parse().unwrap(), 0x10))
+            .collect();
+        let mut state = PexState::default();
+        let start = Instant::now();
+        let first = state.update(&peers, start).unwrap();
+        assert_eq!(first.added.len(), 50);
+        assert!(first.dropped.is_empty());
+
+        // nothing more until a minute has passed, then the rest
+        assert_eq!(state.update(&peers, start + PEX_INTERVAL / 2), None);
+        let second = state.update(&peers, start + PEX_INTERVAL).unwrap();
+        assert_eq!(second.added.len(), 10);
+        for added in first.added.iter().chain(&second.added) {
+            assert_eq!(peers.iter().filter(|peer| *peer == added).count(), 1);
+        }
+
+        // peers that went away are dropped, and no news means no message
+        let later = start + 2 * PEX_INTERVAL;
+        let dropped = state.update(&peers[..58], later).unwrap();
+        assert!(dropped.added.is_empty());
+        let mut gone = dropped.dropped.clone();
+        gone.sort();
+        assert_eq!(gone, [peers[58].0, peers[59].0]);
+        assert_eq!(state.update(&peers[..58], later + PEX_INTERVAL), None);
+    }
+
+    #[tokio::test]
+    async fn test_download_finds_peers_over_pex() {
+        let (data, torrent_file) = test_torrent(3 * TEST_PIECE_LENGTH as usize);
+        let seeder = start_seeders(&data, &[Seeder::Good]).await[0];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+        // has nothing itself, but knows who does
+        tokio::spawn(async move {
+            let mut socket = accept_peer(&listener).await;
+            let handshake = BencodeValue::dict([(
+                "m",
+                BencodeValue::dict([("ut_pex", BencodeValue::from(1))]),
+            )]);
+            write_frame(&mut socket, 20, &[&[0], &handshake.encode()[..]].concat()).await;
+            while let Some((id, payload)) = read_frame(&mut socket).await {
+                if id != 20 || payload[0] != 0 {
+                    continue;
+                }
+                let their_id = decode(&payload[1..])
+                    .unwrap()
+                    .get("m")
+                    .and_then(|m| m.get("ut_pex"))
+                    .and_then(BencodeValue::as_int)
+                    .unwrap() as u8;
+                let pex = PexMessage {
+                    added: vec![(std::net::SocketAddr::V4(seeder), 0x12)],
+                    dropped: vec![],
+                };
+                write_frame(&mut socket, 20, &[&[their_id], &pex.encode()[..]].concat()).await;
+            }
+        });
+
+        let mut storage = MemoryStorage::new(data.len() as u64);
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = vec![addr];
+        tokio::time::timeout(Duration::from_secs(10), torrent.download_to(&mut storage))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.into_inner(), data);
+    }
+
+    #[test]
+    fn test_krpc_round_trip() {
+        // the ping example from BEP 5
+        let ping = Krpc::Query {
+            t: b"aa".to_vec(),
+            id: NodeId(*b"abcdefghij0123456789"),
+            query: Query::Ping,
+        };
+        assert_eq!(
+            ping.encode(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+        let error = Krpc::Error {
+            t: b"aa".to_vec(),
+            code: 201,
+            message: "A Generic Error Ocurred".to_owned(),
+        };
+        assert_eq!(
+            error.encode(),
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee"
+        );
+
+        let node = Node {
+            id: NodeId([7; 20]),
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+        let messages = [
+            ping,
+            error,
+            Krpc::Query {
+                t: b"ab".to_vec(),
+                id: NodeId([1; 20]),
+                query: Query::FindNode(NodeId([2; 20])),
+            },
+            Krpc::Query {
+                t: b"ac".to_vec(),
+                id: NodeId([1; 20]),
+                query: Query::GetPeers([3; 20]),
+            },
+            Krpc::Query {
+                t: b"ad".to_vec(),
+                id: NodeId([1; 20]),
+                query: Query::AnnouncePeer {
+                    info_hash: [3; 20],
+                    port: 6881,
+                    implied_port: true,
+                    token: b"secret".to_vec(),
+                },
+            },
+            Krpc::Query {
+                t: b"ae".to_vec(),
+                id: NodeId([1; 20]),
+                query: Query::Unknown("vote".to_owned()),
+            },
+            Krpc::Response {
+                t: b"ac".to_vec(),
+                response: Response {
+                    id: NodeId([4; 20]),
+                    nodes: vec![node],
+                    values: vec!["10.0.0.2:51413".parse().unwrap()],
+                    token: Some(b"secret".to_vec()),
+                },
+            },
+        ];
+        for msg in messages {
+            assert_eq!(Krpc::decode(&msg.encode()).unwrap(), msg);
+        }
+        assert!(Krpc::decode(b"d1:t2:aa1:y1:qe").is_err());
+        assert!(Krpc::decode(b"d1:rd2:id3:abce1:t2:aa1:y1:re").is_err());
+    }
+
+    #[test]
+    fn test_routing_table() {
+        let us = NodeId([0; 20]);
+        let mut table = RoutingTable::new(us);
+        let node = |first: u8, n: u8| Node {
+            id: NodeId({
+                let mut id = [0; 20];
+                id[0] = first;
+                id[19] = n;
+                id
+            }),
+            addr: std::net::SocketAddrV4::new([10, 0, first, n].into(), 6881),
+        };
+        assert!(!table.insert(Node {
+            id: us,
+            addr: "10.0.0.1:1".parse().unwrap()
+        }));
+
+        // every id with the top bit set shares no prefix with us, one bucket
+        for n in 0..K as u8 {
+            assert!(table.insert(node(0x80, n)));
+        }
+        assert!(!table.insert(node(0x80, 100)));
+        assert!(table.insert(node(0x40, 0)));
+        // a node already there just moves up
+        assert!(table.insert(node(0x80, 0)));
+        assert_eq!(table.len(), K + 1);
+
+        // a node that stopped answering makes room
+        table.failed(node(0x80, 3).addr);
+        table.failed(node(0x80, 3).addr);
+        assert!(!table.nodes().contains(&node(0x80, 3)));
+        assert!(table.insert(node(0x80, 100)));
+        assert_eq!(table.len(), K + 1);
+
+        let closest = table.closest(&node(0x80, 4).id, 3);
+        assert_eq!(closest, [node(0x80, 4), node(0x80, 5), node(0x80, 6)]);
+        assert_eq!(table.closest(&us, 1), [node(0x40, 0)]);
+    }
+
+    async fn start_dht_cluster(n: usize) -> Vec<Dht> {
+        let mut nodes: Vec<Dht> = Vec::new();
+        for _ in 0..n {
+            let dht = Dht::bind("127.0.0.1:0", NodeId::random()).await.unwrap();
+            if let Some(first) = nodes.first() {
+                let std::net::SocketAddr::V4(addr) = first.local_addr().unwrap() else {
+                    unreachable!()
+                };
+                dht.bootstrap(&[addr]).await.unwrap();
+            }
+            nodes.push(dht);
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn test_dht_cluster_finds_announced_peers() {
+        let nodes = start_dht_cluster(12).await;
+        assert!(nodes.iter().all(|dht| dht.len() > 0));
+
+        // any node finds the ones closest to an id
+        let target = nodes[5].id();
+        let found = nodes[9].find_node(target).await;
+        assert_eq!(found.first().map(|node| node.id), Some(target));
+
+        let info_hash = [0x5a; 20];
+        assert!(nodes[3].announce(info_hash, 4321).await.is_empty());
+        nodes[7].announce(info_hash, 4322).await;
+        let mut peers = nodes[11].get_peers(info_hash).await;
+        peers.sort();
+        assert_eq!(
+            peers,
+            [
+                "127.0.0.1:4321".parse().unwrap(),
+                "127.0.0.1:4322".parse().unwrap()
+            ]
+        );
+        assert!(nodes[0].get_peers([0xa5; 20]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dht_checks_tokens() {
+        let dht = Dht::bind("127.0.0.1:0", NodeId::random()).await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(dht.local_addr().unwrap()).await.unwrap();
+        let ask = |query| {
+            Krpc::Query {
+                t: b"tt".to_vec(),
+                id: NodeId([1; 20]),
+                query,
+            }
+            .encode()
+        };
+        let mut buf = vec![0; 1500];
+        let announce = |token: Vec<u8>| Query::AnnouncePeer {
+            info_hash: [9; 20],
+            port: 0,
+            implied_port: true,
+            token,
+        };
+
+        socket
+            .send(&ask(announce(b"made up".to_vec())))
+            .await
+            .unwrap();
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert!(matches!(
+            Krpc::decode(&buf[..len]).unwrap(),
+            Krpc::Error { code: 203, .. }
+        ));
+
+        socket.send(&ask(Query::GetPeers([9; 20]))).await.unwrap();
+        let len = socket.recv(&mut buf).await.unwrap();
+        let Krpc::Response { response, .. } = Krpc::decode(&buf[..len]).unwrap() else {
+            panic!("no answer to get_peers");
+        };
+        assert!(response.values.is_empty());
+        socket
+            .send(&ask(announce(response.token.unwrap())))
+            .await
+            .unwrap();
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert!(matches!(
+            Krpc::decode(&buf[..len]).unwrap(),
+            Krpc::Response { .. }
+        ));
+
+        // implied_port means the port we sent from
+        socket.send(&ask(Query::GetPeers([9; 20]))).await.unwrap();
+        let len = socket.recv(&mut buf).await.unwrap();
+        let Krpc::Response { response, .. } = Krpc::decode(&buf[..len]).unwrap() else {
+            panic!("no answer to get_peers");
+        };
+        let std::net::SocketAddr::V4(ours) = socket.local_addr().unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(response.values, [ours]);
+
+        socket
+            .send(&ask(Query::Unknown("vote".to_owned())))
+            .await
+            .unwrap();
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert!(matches!(
+            Krpc::decode(&buf[..len]).unwrap(),
+            Krpc::Error { code: 204, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dht_state_survives_restart() {
+        let nodes = start_dht_cluster(4).await;
+        nodes[2].announce([1; 20], 1234).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht");
+        let dht = Dht::bind("127.0.0.1:0", NodeId::random())
+            .await
+            .unwrap()
+            .with_state_file(path.clone());
+        let std::net::SocketAddr::V4(first) = nodes[0].local_addr().unwrap() else {
+            unreachable!()
+        };
+        dht.bootstrap(&[first]).await.unwrap();
+        dht.save_state();
+        let state = DhtState::load(&path).unwrap();
+        assert_eq!(state, dht.state());
+        assert_eq!(state.id, dht.id());
+        assert_eq!(state.nodes.len(), 4);
+        drop(dht);
+
+        // the next run knows the network without bootstrap nodes
+        let restarted = Dht::bind("127.0.0.1:0", state.id).await.unwrap();
+        for node in state.nodes {
+            restarted.add_node(node);
+        }
+        restarted.bootstrap(&[]).await.unwrap();
+        assert_eq!(
+            restarted.get_peers([1; 20]).await,
+            ["127.0.0.1:1234".parse().unwrap()]
+        );
+        assert!(DhtState::decode(b"d2:id3:abc5:nodes0:e").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_private_torrent_stays_off_the_dht() {
+        let (data, mut torrent_file) = test_torrent(2 * TEST_PIECE_LENGTH as usize);
+        torrent_file.info.private = Some(1);
+        let addrs = start_seeders(&data, &[Seeder::Good]).await;
+
+        // the only node the DHT knows tells us if anything was sent its way
+        let spy = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(spy_addr) = spy.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let dht = Dht::bind("127.0.0.1:0", NodeId::random()).await.unwrap();
+        dht.add_node(Node {
+            id: NodeId::random(),
+            addr: spy_addr,
+        });
+
+        let mut storage = MemoryStorage::new(data.len() as u64);
+        let mut torrent = Torrent::new(torrent_file);
+        torrent.extra_peers = addrs;
+        torrent.dht = Some(Arc::new(dht));
+        torrent.download_to(&mut storage).await.unwrap();
+        assert_eq!(storage.into_inner(), data);
+        let mut buf = [0; 1500];
+        let sent = tokio::time::timeout(Duration::from_millis(100), spy.recv(&mut buf)).await;
+        assert!(sent.is_err(), "a private torrent went to the DHT");
+    }
 }
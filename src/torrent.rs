@@ -1,11 +1,34 @@
-use crate::peer::{Message, MessageId, Peer, PiecePayload, RequestPayload};
-use bytes::Bytes;
+use crate::bencode;
+use crate::bitfield::Bitfield;
+use crate::dht::Dht;
+use crate::magnet::Magnet;
+use crate::metadata;
+use crate::peer::{Peer, LISTEN_PORT, PEER_ID};
+use crate::resume::{resume_path, ResumeData};
+use crate::scheduler::PartialPiece;
+use crate::scheduler::{BlockRequest, PeerKey};
+use crate::server::Server;
+use crate::storage::{FileStorage, MmapStorage, Storage};
+use crate::swarm::{Progress, Swarm, DEFAULT_MAX_REQUESTS};
+use crate::tracker::TrackerTiers;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::future::Future;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
-const DEFAULT_BLOCK_SIZE: u32 = 1 << 14;
+pub const DEFAULT_BLOCK_SIZE: u32 = 1 << 14;
+// how long a piece lasts when playing in sequential mode
+const SEQUENTIAL_PIECE_TIME: Duration = Duration::from_secs(1);
+// how often the resume file is brought up to date
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+// how long one peer gets to hand over the info dict of a magnet link
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum TorrentState {
     Init,
@@ -21,233 +44,643 @@ pub enum DownloadState {
 }
 #[derive(Debug)]
 pub struct Piece {
-    index: u32,
-    state: DownloadState,
-    n_blocks: u32,
-}
-#[derive(Debug)]
-pub struct Block {
-    index: u32,
-    state: DownloadState,
-    bytes: Vec<u8>,
-    //[u8; DEFAULT_BLOCK_SIZE as usize],
+    pub index: u32,
+    pub state: DownloadState,
+    pub n_blocks: u32,
+    pub size: u32,
+    pub blocks: Vec<BlockState>,
+    // the piece is put together here while it downloads
+    pub data: Vec<u8>,
+    // peers that sent us a corrupt copy of this piece
+    pub bad_peers: Vec<PeerKey>,
+    // a corrupt copy came from several peers, so the next one comes from
+    // just one of them and there is someone to blame if it's bad again
+    pub one_peer: bool,
+    // when the piece is needed by, in streaming mode
+    pub deadline: Option<Instant>,
 }
+impl Piece {
+    pub fn new(index: u32, size: u32) -> Self {
+        let n_blocks = size.div_ceil(DEFAULT_BLOCK_SIZE);
+        Self {
+            index,
+            state: DownloadState::Zero,
+            n_blocks,
+            size,
+            blocks: vec![BlockState::Missing; n_blocks as usize],
+            data: Vec::new(),
+            bad_peers: Vec::new(),
+            one_peer: false,
+            deadline: None,
+        }
+    }
+
+    /// Makes room for the piece's data the first time a block is requested.
+    pub fn start(&mut self) {
+        if let DownloadState::Zero = self.state {
+            self.state = DownloadState::Partial;
+            self.data = vec![0; self.size as usize];
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = DownloadState::Zero;
+        self.blocks = vec![BlockState::Missing; self.n_blocks as usize];
+        self.data = Vec::new();
+    }
+
+    /// True if `peer` may take blocks of this piece as far as `one_peer` goes.
+    pub fn open_to(&self, peer: PeerKey) -> bool {
+        !self.one_peer
+            || self.blocks.iter().all(|b| match b {
+                BlockState::Requested(other) | BlockState::Received(Some(other)) => *other == peer,
+                _ => true,
+            })
+    }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PeersRequest<'a> {
-    peer_id: &'a str,
-    port: u32,
-    uploaded: u32,
-    downloaded: u32,
-    left: u32,
-    compact: u32,
+    pub fn block_request(&self, block: u32) -> BlockRequest {
+        let begin = block * DEFAULT_BLOCK_SIZE;
+        BlockRequest {
+            piece: self.index,
+            begin,
+            length: DEFAULT_BLOCK_SIZE.min(self.size - begin),
+        }
+    }
 }
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlockState {
+    Missing,
+    Requested(PeerKey),
+    // and who sent it, nobody for blocks from the resume file
+    Received(Option<PeerKey>),
+}
+impl BlockState {
+    pub fn is_received(&self) -> bool {
+        matches!(self, BlockState::Received(_))
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub struct TorrentFile {
-    pub announce: String,
+    // torrents from magnet links may not have a tracker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    // tiers of trackers (BEP 12), used instead of announce when present
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: Info,
+    // SHA-1 of the info dict exactly as it appears in the .torrent file
+    #[serde(skip)]
+    pub info_hash: [u8; 20],
+}
+impl TorrentFile {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut torrent_file: TorrentFile = serde_bencode::from_bytes(bytes)?;
+
+        // Info only knows the keys we use, so hash the original bytes rather than
+        // re-encoding it, or keys like `source` would be missing from the hash.
+        let span = bencode::dict_value_span(bytes, b"info")?
+            .ok_or_else(|| anyhow::anyhow!("torrent has no info dict"))?;
+        torrent_file.info_hash = Sha1::digest(&bytes[span]).into();
+        torrent_file.info.validate()?;
+        Ok(torrent_file)
+    }
 }
 pub struct Torrent {
     pub torrent_file: TorrentFile,
     pub n_pieces: u32,
-    pub peers: Vec<Peer>,
-    pub pieces: Vec<Piece>,
+    // peers we know about without asking the tracker, e.g. from a magnet link
+    pub extra_peers: Vec<SocketAddrV4>,
+    pub trackers: TrackerTiers,
+    // how many block requests to keep out with each peer at most
+    pub max_requests: usize,
+    // download in order, e.g. to play a file while it downloads
+    pub sequential: bool,
+    // map the target files into memory instead of writing to them
+    pub mmap: bool,
+    // finds peers without a tracker
+    pub dht: Option<Arc<Dht>>,
 }
 impl Torrent {
     pub fn from_file(filename: String) -> Self {
         let file: Vec<u8> = std::fs::read(&filename).unwrap();
-        let torrent_file: TorrentFile = serde_bencode::from_bytes(&file).unwrap();
-
-        let n_pieces =
-            (torrent_file.info.length as f32 / torrent_file.info.piece_length as f32).ceil() as u32;
-        let mut pieces = Vec::with_capacity(n_pieces as usize);
-        for index in 0..n_pieces {
-            let n_blocks;
-            if index == n_pieces - 1 {
-                n_blocks = (((torrent_file.info.length % torrent_file.info.piece_length) as f32
-                    / DEFAULT_BLOCK_SIZE as f32)
-                    .ceil()) as u32;
-            } else {
-                n_blocks = (torrent_file.info.piece_length as f32 / DEFAULT_BLOCK_SIZE as f32)
-                    .ceil() as u32;
+        let torrent_file = TorrentFile::from_bytes(&file).unwrap();
+        Self::new(torrent_file)
+    }
+
+    /// Looks for peers of a magnet link and fetches the info dict from the
+    /// first one that has it. The DHT is only asked if nobody else has it,
+    /// until then we can't know whether the torrent is private.
+    pub async fn from_magnet(magnet: &Magnet, dht: Option<Arc<Dht>>) -> anyhow::Result<Self> {
+        let mut peers: Vec<SocketAddrV4> = Vec::new();
+        for peer in &magnet.peers {
+            match tokio::net::lookup_host(peer).await {
+                Ok(addrs) => peers.extend(addrs.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => println!("could not resolve {}: {}", peer, e),
+            }
+        }
+        // every tracker of a magnet link gets a tier of its own
+        let mut trackers =
+            TrackerTiers::new(magnet.trackers.iter().map(|t| vec![t.clone()]).collect());
+        if !trackers.is_empty() {
+            // we don't know the size yet, anything but 0 so we don't look like a seed
+            match trackers.announce(&magnet.info_hash, 1).await {
+                Ok(res) => peers.extend(res),
+                Err(e) => println!("{}", e),
             }
+        }
 
-            pieces.push(Piece {
-                index,
-                state: DownloadState::Zero,
-                n_blocks,
-            });
+        let mut info_bytes = Self::fetch_metadata_from_any(&peers, magnet, METADATA_TIMEOUT).await;
+        if let (None, Some(dht)) = (&info_bytes, &dht) {
+            let found: Vec<SocketAddrV4> = dht
+                .get_peers(magnet.info_hash)
+                .await
+                .into_iter()
+                .filter(|peer| !peers.contains(peer))
+                .collect();
+            dht.save_state();
+            info_bytes = Self::fetch_metadata_from_any(&found, magnet, METADATA_TIMEOUT).await;
+            peers.extend(found);
         }
+        let Some(info_bytes) = info_bytes else {
+            anyhow::bail!("none of {} peers sent the metadata", peers.len());
+        };
+        let torrent_file = TorrentFile {
+            announce: magnet.trackers.first().cloned(),
+            announce_list: None,
+            info: serde_bencode::from_bytes(&info_bytes)?,
+            info_hash: magnet.info_hash,
+        };
+        torrent_file.info.validate()?;
+        let mut torrent = Self::new(torrent_file);
+        torrent.extra_peers = peers;
+        torrent.trackers = trackers;
+        torrent.dht = dht;
+        Ok(torrent)
+    }
 
-        Self {
-            torrent_file,
-            n_pieces,
-            peers: Vec::new(), // defined for now in client::from_torrent_file.
-            // should be moved to this function
-            pieces,
+    /// The info dict from the first of `peers` that sends it. Each one gets
+    /// `timeout` for the whole exchange, so a silent peer can't hold us up.
+    pub async fn fetch_metadata_from_any(
+        peers: &[SocketAddrV4],
+        magnet: &Magnet,
+        timeout: Duration,
+    ) -> Option<Vec<u8>> {
+        for addr in peers {
+            let fetch = Self::fetch_metadata_from(*addr, magnet, PEER_ID);
+            match tokio::time::timeout(timeout, fetch).await {
+                Ok(Ok(info_bytes)) => return Some(info_bytes),
+                Ok(Err(e)) => println!("no metadata from {}: {}", addr, e),
+                Err(_) => println!("no metadata from {}: timed out", addr),
+            }
         }
+        None
     }
 
-    pub async fn get_peers(self: &Self) -> PeersResponse {
-        let info_hash = self.torrent_file.info.hash();
-        let peers_req = PeersRequest {
-            peer_id: "00112233445566778899",
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: self.torrent_file.info.length,
-            compact: 1,
-        };
-        let url = format!(
-            "{}?{}&info_hash={}",
-            &self.torrent_file.announce,
-            serde_urlencoded::to_string(peers_req).unwrap(),
-            urlencode_info_hash(&info_hash)
-        );
+    async fn fetch_metadata_from(
+        addr: SocketAddrV4,
+        magnet: &Magnet,
+        peer_id: [u8; 20],
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut peer = Peer::connect(addr.to_string()).await?;
+        let handshake = peer.handshake(magnet.info_hash, peer_id).await?;
+        if !handshake.supports_extensions() {
+            anyhow::bail!("peer does not support the extension protocol");
+        }
+        metadata::fetch_metadata(&mut peer, &magnet.info_hash).await
+    }
 
-        let x = reqwest::get(url).await.unwrap();
-        let y: Vec<u8> = x.bytes().await.unwrap().to_vec();
-        let z: PeersResponse = serde_bencode::from_bytes(&y.as_slice()).unwrap();
-        z
+    pub fn new(torrent_file: TorrentFile) -> Self {
+        let trackers = TrackerTiers::from_torrent(&torrent_file);
+        let n_pieces = torrent_file.info.n_pieces();
+
+        Self {
+            torrent_file,
+            n_pieces,
+            extra_peers: Vec::new(),
+            trackers,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            sequential: false,
+            mmap: false,
+            dht: None,
+        }
     }
 
-    pub async fn peer_ips(self: &Self) -> Vec<SocketAddrV4> {
-        let peers_res = self.get_peers().await;
-        let mut peers: Vec<SocketAddrV4> = Vec::new();
-        for chunk in peers_res.peers.chunks_exact(6) {
-            peers.push(SocketAddrV4::new(
-                Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
-                u16::from_be_bytes([chunk[4], chunk[5]]),
-            ));
+    pub async fn peer_ips(&mut self) -> Vec<SocketAddrV4> {
+        let mut peers = self.extra_peers.clone();
+        if !self.trackers.is_empty() {
+            let left = self.torrent_file.info.total_length();
+            match self
+                .trackers
+                .announce(&self.torrent_file.info_hash, left)
+                .await
+            {
+                Ok(res) => {
+                    for peer in res {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+        if let Some(dht) = self.public_dht() {
+            let info_hash = self.torrent_file.info_hash;
+            for peer in dht.announce(info_hash, LISTEN_PORT).await {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+            dht.save_state();
         }
         peers
     }
 
-    pub async fn download_piece(self: &mut Self, piece_index: u32, filename: String) -> Vec<u8> {
-        let n_blocks = self.pieces[piece_index as usize].n_blocks;
-        // (self.torrent_file.info.piece_length as f32 / DEFAULT_BLOCK_SIZE as f32).ceil() as u32;
-        let mut blocks: Vec<Block> = Vec::new();
-
-        for block_index in 0..n_blocks {
-            let block_message = self.download_block(piece_index, block_index).await;
-            let block =
-                PiecePayload::from_bytes(block_message.payload.as_ref().unwrap().to_owned());
-            blocks.push(Block {
-                index: block.index,
-                state: DownloadState::Complete,
-                bytes: block.block_bytes,
-            });
+    /// The DHT, unless the torrent is private and must stay off it.
+    fn public_dht(&self) -> Option<&Dht> {
+        match self.torrent_file.info.is_private() {
+            true => None,
+            false => self.dht.as_deref(),
         }
+    }
 
-        println!("got the whole piece now");
-        blocks.sort_by(|a, b| a.index.cmp(&b.index));
-
-        // write to file
-        let mut bytes: Vec<u8> = Vec::new();
-        for block in blocks {
-            bytes.extend(block.bytes);
+    /// Downloads a single piece from the swarm and writes it to `filename`.
+    pub async fn download_piece(
+        &mut self,
+        piece_index: u32,
+        filename: String,
+    ) -> anyhow::Result<Vec<u8>> {
+        if piece_index >= self.n_pieces {
+            anyhow::bail!("torrent only has {} pieces", self.n_pieces);
         }
+        let peers = self.peer_ips().await;
+        let mut bytes = Vec::new();
+        self.swarm()
+            .download(peers, [piece_index], |progress| {
+                if let Progress::Piece(_, piece) = progress {
+                    bytes = piece;
+                }
+                Ok(())
+            })
+            .await?;
+
         println!("attempting write to {}", &filename);
-        std::fs::write(&filename, &bytes).expect("error writing to file");
+        // a file that holds just this piece
+        let mut storage = FileStorage::open(&[FileEntry {
+            path: PathBuf::from(&filename),
+            length: bytes.len() as u64,
+            offset: 0,
+        }])?;
+        storage.preallocate()?;
+        storage.write_at(0, &bytes)?;
+        storage.flush()?;
         println!("Piece {} downloaded to {}", piece_index, &filename);
+        Ok(bytes)
+    }
 
-        bytes
+    /// Downloads the torrent to `target_filename`. Pieces already on disk from
+    /// an earlier run are kept, so an interrupted download picks up where it
+    /// left off. A resume file next to the target saves hashing them all again,
+    /// it's brought up to date on Ctrl-C too.
+    pub async fn download(&mut self, target_filename: String) -> anyhow::Result<()> {
+        self.download_until(target_filename, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
     }
 
-    pub async fn download_block(self: &mut Self, piece_index: u32, block_index: u32) -> Message {
-        let length: u32;
-        if piece_index == self.n_pieces - 1
-            && block_index == self.pieces[piece_index as usize].n_blocks - 1
-        {
-            length = (self.torrent_file.info.length % self.torrent_file.info.piece_length)
-                % DEFAULT_BLOCK_SIZE;
-        } else {
-            length = DEFAULT_BLOCK_SIZE;
+    /// Like `download`, but gives up when `stop` resolves instead of on Ctrl-C.
+    pub async fn download_until(
+        &mut self,
+        target_filename: String,
+        stop: impl Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let info = self.torrent_file.info.clone();
+        let info_hash = self.torrent_file.info_hash;
+        let layout = info.file_layout(Path::new(&target_filename))?;
+        let mut storage = self.open_storage(&target_filename, &layout)?;
+        let resume_file = resume_path(&target_filename);
+        let (done, saved_partial) = match self.saved_resume(&layout, &resume_file) {
+            Some(resume) => (resume.have, resume.partial),
+            None => (storage.check_pieces(&info)?, Vec::new()),
+        };
+        let mut partial = Vec::new();
+        for (index, blocks) in saved_partial {
+            if index >= self.n_pieces || done[index as usize] {
+                continue;
+            }
+            partial.push(PartialPiece {
+                index,
+                blocks,
+                data: storage.read_piece(&info, index)?,
+            });
         }
 
-        // send request message
-        let payload = RequestPayload {
-            index: piece_index,
-            begin: block_index * DEFAULT_BLOCK_SIZE,
-            length: length.to_be_bytes(),
+        let save = |have: &[bool], partial| {
+            ResumeData::new(info_hash, have.to_vec(), &layout, partial)
+                .save(&resume_file)
+                .map_err(|e| anyhow::anyhow!("error saving resume data: {}", e))
+        };
+        self.download_into(storage.as_mut(), done, partial, stop, save)
+            .await
+    }
+
+    /// Uploads the pieces of `target_filename` that are on disk to anyone who
+    /// asks, until something goes wrong.
+    pub async fn seed(&mut self, target_filename: String) -> anyhow::Result<()> {
+        let info = self.torrent_file.info.clone();
+        let info_hash = self.torrent_file.info_hash;
+        let layout = info.file_layout(Path::new(&target_filename))?;
+        let mut storage = self.open_storage(&target_filename, &layout)?;
+        let resume_file = resume_path(&target_filename);
+        let have = match self.saved_resume(&layout, &resume_file) {
+            Some(resume) => resume.have,
+            None => storage.check_pieces(&info)?,
         };
-        let request_msg = Message::new_request_message(payload);
-        // TODO: check that peer is handshook
-        self.peers[0].send(request_msg.into()).await.unwrap();
+        println!(
+            "seeding {} of {} pieces on port {}",
+            have.iter().filter(|&&have| have).count(),
+            self.n_pieces,
+            LISTEN_PORT
+        );
+        // let the trackers know we're here, there's nothing left for us to get
+        if !self.trackers.is_empty() {
+            if let Err(e) = self.trackers.announce(&info_hash, 0).await {
+                println!("{}", e);
+            }
+        }
+        // and the DHT, which keeps answering other nodes while we seed
+        if let Some(dht) = self.public_dht() {
+            dht.announce(info_hash, LISTEN_PORT).await;
+            dht.save_state();
+        }
+        Server::new(PEER_ID)
+            .with_torrent(info, info_hash, Bitfield::from(&have[..]), storage)
+            .listen(LISTEN_PORT)
+            .await
+    }
 
-        // get piece_msg
-        let msg = self.peers[0].wait_for_msg(MessageId::Piece).await.unwrap();
+    fn open_storage(
+        &self,
+        target_filename: &str,
+        layout: &[FileEntry],
+    ) -> anyhow::Result<Box<dyn Storage>> {
+        match self.mmap {
+            true => MmapStorage::open(layout).map(|s| Box::new(s) as Box<dyn Storage>),
+            false => FileStorage::open(layout).map(|s| Box::new(s) as Box<dyn Storage>),
+        }
+        .and_then(|mut storage| storage.preallocate().map(|_| storage))
+        .map_err(|e| anyhow::anyhow!("error opening {}: {}", target_filename, e))
+    }
 
-        // println!("got message: {:#?}", msg.message_id);
-        // println!("length: {:#?}", msg.length);
-        // println!("block: {:#?}/ {}", block_index, n_blocks);
+    /// The resume file for a download, if it still matches what's on disk.
+    fn saved_resume(&self, layout: &[FileEntry], resume_file: &Path) -> Option<ResumeData> {
+        ResumeData::load(resume_file)
+            .ok()
+            .filter(|resume| resume.matches(&self.torrent_file.info_hash, self.n_pieces, layout))
+    }
 
-        msg
+    /// Downloads the torrent into `storage`, keeping whatever good pieces it
+    /// has already.
+    #[allow(dead_code)]
+    pub async fn download_to(&mut self, storage: &mut dyn Storage) -> anyhow::Result<()> {
+        storage.preallocate()?;
+        let done = storage.check_pieces(&self.torrent_file.info)?;
+        self.download_into(storage, done, Vec::new(), std::future::pending(), |_, _| {
+            Ok(())
+        })
+        .await
     }
 
-    pub async fn download(self: &mut Self, target_filename: String) {
-        let mut data: Vec<u8> = Vec::new();
-        for piece_index in 0..self.n_pieces {
-            let piece_bytes = self
-                .download_piece(piece_index, format!("{}-{}", target_filename, piece_index))
-                .await;
-            // TODO check hash of piece file
+    /// Downloads the pieces `done` says are missing into `storage`, until
+    /// `stop` resolves. Every so often, and when the download ends either way,
+    /// the blocks of unfinished pieces are written out too, and `save` gets to
+    /// record what is in storage by then.
+    async fn download_into(
+        &mut self,
+        storage: &mut dyn Storage,
+        mut done: Vec<bool>,
+        partial: Vec<PartialPiece>,
+        stop: impl Future<Output = ()> + Send + 'static,
+        mut save: impl FnMut(&[bool], Vec<(u32, Vec<bool>)>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let have = Bitfield::from(&done[..]);
+        let wanted: Vec<u32> = have.missing().map(|i| i as u32).collect();
+        if wanted.len() < self.n_pieces as usize {
+            println!(
+                "resuming with {} of {} pieces",
+                self.n_pieces as usize - wanted.len(),
+                self.n_pieces
+            );
+        }
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        let peers = self.peer_ips().await;
 
-            data.extend(piece_bytes);
+        let info = self.torrent_file.info.clone();
+        let mut swarm = self
+            .swarm()
+            .with_have(have)
+            .with_partial(partial)
+            .with_checkpoints(RESUME_INTERVAL)
+            .with_stop(stop);
+        // in sequential mode the cursor sits on the first piece we don't have yet
+        let mut cursor = None;
+        if self.sequential {
+            let first = done.iter().position(|&done| !done).unwrap_or(done.len());
+            let (tx, rx) = watch::channel(first as u32);
+            swarm = swarm.with_cursor(rx, SEQUENTIAL_PIECE_TIME);
+            cursor = Some(tx);
         }
+        swarm
+            .download(peers, wanted, |progress| match progress {
+                Progress::Piece(index, piece) => {
+                    storage.write_block(&info, index, 0, &piece)?;
+                    println!("Piece {} downloaded", index);
+
+                    done[index as usize] = true;
+                    if let Some(cursor) = &cursor {
+                        cursor.send_if_modified(|cursor| {
+                            let old = *cursor;
+                            while done.get(*cursor as usize) == Some(&true) {
+                                *cursor += 1;
+                            }
+                            *cursor != old
+                        });
+                    }
+                    Ok(())
+                }
+                Progress::Partial(pieces) => {
+                    // the blocks go to storage first, so what gets saved never
+                    // promises anything that isn't there
+                    let mut saved = Vec::with_capacity(pieces.len());
+                    for piece in pieces {
+                        for (block, _) in piece.blocks.iter().enumerate().filter(|(_, &b)| b) {
+                            let begin = block * DEFAULT_BLOCK_SIZE as usize;
+                            let end = (begin + DEFAULT_BLOCK_SIZE as usize).min(piece.data.len());
+                            storage.write_block(
+                                &info,
+                                piece.index,
+                                begin as u32,
+                                &piece.data[begin..end],
+                            )?;
+                        }
+                        saved.push((piece.index, piece.blocks));
+                    }
+                    storage.flush()?;
+                    save(&done, saved)
+                }
+            })
+            .await
+    }
 
-        std::fs::write(&target_filename, data)
-            .expect(&format!("error writing to {}", target_filename));
+    fn swarm(&self) -> Swarm {
+        Swarm::new(
+            self.torrent_file.info.clone(),
+            self.torrent_file.info_hash,
+            PEER_ID,
+        )
+        .with_max_requests(self.max_requests)
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
 pub struct Info {
-    pub length: u32,
+    // single-file torrents have a length, multi-file torrents have a list of files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileInfo>>,
+    // the file name, or the name of the top level directory for multi-file torrents
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
     pub pieces: serde_bytes::ByteBuf,
+    // 1 keeps the torrent off the DHT and out of peer exchange (BEP 27)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
 }
 impl Info {
-    pub fn hash(&self) -> [u8; 20] {
-        let bytes = serde_bencode::to_bytes(&self).unwrap();
-        let mut hasher = Sha1::new();
-        hasher.update(bytes);
-        let hash: [u8; 20] = hasher.finalize().try_into().unwrap();
-        hash
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
     }
-}
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct PeersResponse {
-    // An integer, indicating how often your client should make a request to the tracker.
-    interval: u32,
+    /// Checks that the pieces add up to the length, everything that works
+    /// with piece indices and sizes relies on it.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.piece_length == 0 {
+            anyhow::bail!("piece length is 0");
+        }
+        if !self.pieces.len().is_multiple_of(20) {
+            anyhow::bail!(
+                "pieces is {} bytes, not a list of hashes",
+                self.pieces.len()
+            );
+        }
+        let expected = self.total_length().div_ceil(self.piece_length as u64);
+        if self.n_pieces() as u64 != expected {
+            anyhow::bail!(
+                "{} piece hashes for {} bytes in pieces of {}",
+                self.n_pieces(),
+                self.total_length(),
+                self.piece_length
+            );
+        }
+        Ok(())
+    }
 
-    // A string, which contains list of peers that your client can connect to.
-    // Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
-    pub peers: serde_bytes::ByteBuf,
-}
-#[allow(dead_code)]
-impl PeersResponse {
-    pub fn from_bytes(bytes: Bytes) -> Self {
-        let x = bytes.chunks_exact(6);
-        for chunk in x {
-            println!("chunk {:#?}", chunk);
+    /// The 20 byte SHA-1 hash of the piece at `index`.
+    pub fn piece_hash(&self, index: u32) -> &[u8] {
+        let start = index as usize * 20;
+        &self.pieces[start..start + 20]
+    }
+
+    pub fn verify_piece(&self, index: u32, bytes: &[u8]) -> bool {
+        if bytes.len() != self.piece_size(index) as usize {
+            return false;
         }
-        // let decoded = serde_bencode::from_bytes(x).unwrap();
-        PeersResponse {
-            interval: 0,
-            peers: serde_bytes::ByteBuf::new(),
+        let hash: [u8; 20] = Sha1::digest(bytes).into();
+        hash == self.piece_hash(index)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.length.unwrap_or(0),
         }
     }
-}
 
-fn urlencode_info_hash(hash: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * hash.len());
-    for &byte in hash {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+    pub fn n_pieces(&self) -> u32 {
+        (self.pieces.len() / 20) as u32
     }
-    encoded
+
+    /// Length in bytes of the piece at `index`. Only the last piece can be short.
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length as u64;
+        (self.total_length() - start).min(self.piece_length as u64) as u32
+    }
+
+    /// Lays the files of this torrent out in the global byte space, rooted at `root`.
+    /// A single-file torrent is written to `root` itself, a multi-file torrent
+    /// treats `root` as a directory and every file path is relative to it.
+    pub fn file_layout(&self, root: &Path) -> anyhow::Result<Vec<FileEntry>> {
+        let files = match &self.files {
+            None => {
+                return Ok(vec![FileEntry {
+                    path: root.to_path_buf(),
+                    length: self.total_length(),
+                    offset: 0,
+                }])
+            }
+            Some(files) => files,
+        };
+
+        let mut layout = Vec::with_capacity(files.len());
+        let mut offset = 0;
+        for file in files {
+            if file.path.is_empty() {
+                anyhow::bail!("file with empty path in torrent");
+            }
+            let mut path = root.to_path_buf();
+            for component in &file.path {
+                // don't let a torrent write outside of the target directory
+                let mut parts = Path::new(component).components();
+                match (parts.next(), parts.next()) {
+                    (Some(Component::Normal(part)), None) => path.push(part),
+                    _ => anyhow::bail!("invalid path component in torrent: {:?}", component),
+                }
+            }
+            layout.push(FileEntry {
+                path,
+                length: file.length,
+                offset,
+            });
+            offset += file.length;
+        }
+        Ok(layout)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct FileInfo {
+    pub length: u64,
+    // path components, the last one is the file name
+    pub path: Vec<String>,
+}
+
+/// A file on disk and the range of the torrent's byte space that it covers.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: u64,
+    pub offset: u64,
 }
@@ -0,0 +1,406 @@
+use crate::peer::{LISTEN_PORT, PEER_ID};
+use crate::torrent::TorrentFile;
+use bytes::Bytes;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+// BEP 15
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+// a connection id can be used for a minute after we got it
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+// wait 15 * 2^n seconds for the nth retransmission. The spec goes up to
+// n = 8, over two hours against a dead tracker, so we give up after n = 2
+const UDP_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const UDP_MAX_RETRIES: u32 = 2;
+// how long one tracker in the tiers gets before we move on to the next. More
+// than the 15 + 30 + 60 s a silent udp tracker takes, that one ends on its own
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PeersRequest<'a> {
+    peer_id: &'a str,
+    port: u32,
+    uploaded: u32,
+    downloaded: u32,
+    left: u64,
+    compact: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PeersResponse {
+    // An integer, indicating how often your client should make a request to the tracker.
+    interval: u32,
+
+    // A string, which contains list of peers that your client can connect to.
+    // Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    pub peers: serde_bytes::ByteBuf,
+}
+impl PeersResponse {
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: Bytes) -> Self {
+        let x = bytes.chunks_exact(6);
+        for chunk in x {
+            println!("chunk {:#?}", chunk);
+        }
+        // let decoded = serde_bencode::from_bytes(x).unwrap();
+        PeersResponse {
+            interval: 0,
+            peers: serde_bytes::ByteBuf::new(),
+        }
+    }
+
+    pub fn peer_ips(&self) -> Vec<SocketAddrV4> {
+        let mut peers: Vec<SocketAddrV4> = Vec::new();
+        for chunk in self.peers.chunks_exact(6) {
+            peers.push(SocketAddrV4::new(
+                Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
+                u16::from_be_bytes([chunk[4], chunk[5]]),
+            ));
+        }
+        peers
+    }
+}
+
+/// Announces to the tracker at `announce`, over HTTP or UDP depending on the
+/// url, and returns the peers it knows about.
+pub async fn peer_ips(
+    announce: &str,
+    info_hash: &[u8; 20],
+    left: u64,
+) -> anyhow::Result<Vec<SocketAddrV4>> {
+    if announce.starts_with("udp://") {
+        let mut tracker = UdpTracker::new(announce).await?;
+        Ok(tracker.announce(info_hash, left).await?.peers)
+    } else {
+        Ok(get_peers(announce, info_hash, left).await?.peer_ips())
+    }
+}
+
+/// Trackers grouped in tiers (BEP 12). Tiers are tried in order, and within a
+/// tier the trackers are tried in order until one answers. A tracker that
+/// answers moves to the front of its tier so it is asked first next time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    timeout: Duration,
+}
+impl TrackerTiers {
+    /// Shuffles every tier, which the spec wants done once when the torrent is loaded.
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        let mut rng = rand::thread_rng();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rng);
+        }
+        Self {
+            tiers,
+            timeout: TRACKER_TIMEOUT,
+        }
+    }
+
+    /// Changes how long each tracker gets to answer.
+    #[allow(dead_code)]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Uses `announce-list` if the torrent has one, `announce` otherwise.
+    pub fn from_torrent(torrent_file: &TorrentFile) -> Self {
+        match &torrent_file.announce_list {
+            Some(list) if list.iter().any(|tier| !tier.is_empty()) => Self::new(list.clone()),
+            _ => Self::new(
+                torrent_file
+                    .announce
+                    .iter()
+                    .map(|a| vec![a.clone()])
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    #[allow(dead_code)]
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        left: u64,
+    ) -> anyhow::Result<Vec<SocketAddrV4>> {
+        let info_hash = *info_hash;
+        self.announce_with(|url| async move { peer_ips(&url, &info_hash, left).await })
+            .await
+    }
+
+    /// Walks the tiers calling `announce` on each tracker until one succeeds.
+    /// A tracker that takes longer than the timeout counts as failed.
+    pub async fn announce_with<F, Fut>(
+        &mut self,
+        mut announce: F,
+    ) -> anyhow::Result<Vec<SocketAddrV4>>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<SocketAddrV4>>>,
+    {
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let attempt = tokio::time::timeout(self.timeout, announce(tier[i].clone()));
+                match attempt
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")))
+                {
+                    Ok(peers) => {
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        return Ok(peers);
+                    }
+                    Err(e) => eprintln!("tracker {} failed: {}", tier[i], e),
+                }
+            }
+        }
+        anyhow::bail!("no tracker answered")
+    }
+}
+
+/// Announces to an HTTP tracker. `left` is how many bytes we still need.
+pub async fn get_peers(
+    announce: &str,
+    info_hash: &[u8; 20],
+    left: u64,
+) -> anyhow::Result<PeersResponse> {
+    let peers_req = PeersRequest {
+        peer_id: std::str::from_utf8(&PEER_ID)?,
+        port: LISTEN_PORT as u32,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        compact: 1,
+    };
+    let url = format!(
+        "{}?{}&info_hash={}",
+        announce,
+        serde_urlencoded::to_string(peers_req)?,
+        urlencode_info_hash(info_hash)
+    );
+
+    let res = reqwest::get(url).await?;
+    let bytes = res.bytes().await?;
+    Ok(serde_bencode::from_bytes(&bytes)?)
+}
+
+fn urlencode_info_hash(hash: &[u8; 20]) -> String {
+    let mut encoded = String::with_capacity(3 * hash.len());
+    for &byte in hash {
+        encoded.push('%');
+        encoded.push_str(&hex::encode([byte]));
+    }
+    encoded
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct AnnounceResponse {
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<SocketAddrV4>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ScrapeResponse {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// A client for one UDP tracker (BEP 15).
+pub struct UdpTracker {
+    socket: UdpSocket,
+    // connection id and when we got it
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retries: u32,
+}
+impl UdpTracker {
+    pub async fn new(url: &str) -> anyhow::Result<Self> {
+        let host = url
+            .strip_prefix("udp://")
+            .ok_or_else(|| anyhow::anyhow!("not a udp tracker: {}", url))?;
+        let host = host.split('/').next().unwrap_or(host);
+        let addr = tokio::net::lookup_host(host)
+            .await?
+            .find(SocketAddr::is_ipv4)
+            .ok_or_else(|| anyhow::anyhow!("could not resolve {}", host))?;
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            connection: None,
+            base_timeout: UDP_BASE_TIMEOUT,
+            max_retries: UDP_MAX_RETRIES,
+        })
+    }
+
+    /// Changes the retransmit schedule, the spec's is a bit slow for tests.
+    #[allow(dead_code)]
+    pub fn with_timeout(mut self, base_timeout: Duration, max_retries: u32) -> Self {
+        self.base_timeout = base_timeout;
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        left: u64,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let key: u32 = rand::random();
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(info_hash);
+        body.extend_from_slice(&PEER_ID);
+        body.extend_from_slice(&0u64.to_be_bytes()); // downloaded
+        body.extend_from_slice(&left.to_be_bytes());
+        body.extend_from_slice(&0u64.to_be_bytes()); // uploaded
+        body.extend_from_slice(&0u32.to_be_bytes()); // event: none
+        body.extend_from_slice(&0u32.to_be_bytes()); // ip: the one we send from
+        body.extend_from_slice(&key.to_be_bytes());
+        body.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: default
+        body.extend_from_slice(&LISTEN_PORT.to_be_bytes());
+
+        let res = self.request(ACTION_ANNOUNCE, &body).await?;
+        if res.len() < 12 {
+            anyhow::bail!("announce response too short");
+        }
+        let peers = res[12..]
+            .chunks_exact(6)
+            .map(|chunk| {
+                SocketAddrV4::new(
+                    Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
+                    u16::from_be_bytes([chunk[4], chunk[5]]),
+                )
+            })
+            .collect();
+        Ok(AnnounceResponse {
+            interval: read_u32(&res, 0),
+            leechers: read_u32(&res, 4),
+            seeders: read_u32(&res, 8),
+            peers,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> anyhow::Result<Vec<ScrapeResponse>> {
+        let res = self.request(ACTION_SCRAPE, &info_hashes.concat()).await?;
+        if res.len() < 12 * info_hashes.len() {
+            anyhow::bail!("scrape response too short");
+        }
+        Ok(res
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|chunk| ScrapeResponse {
+                seeders: read_u32(chunk, 0),
+                completed: read_u32(chunk, 4),
+                leechers: read_u32(chunk, 8),
+            })
+            .collect())
+    }
+
+    /// Sends a request that needs a connection id and returns the response body.
+    /// The connection id is checked before every retransmission, since waiting
+    /// on a slow tracker can easily outlast it. Connecting and the request
+    /// itself share one retransmit count, so the wait keeps doubling whichever
+    /// of them the tracker doesn't answer.
+    async fn request(&mut self, action: u32, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut n = 0;
+        while n <= self.max_retries {
+            let connection_id = match self.connection {
+                Some((id, since)) if since.elapsed() < CONNECTION_ID_TTL => Some(id),
+                _ => self.connect(n).await?,
+            };
+            if let Some(connection_id) = connection_id {
+                if let Some(res) = self.exchange(connection_id, action, body, n).await? {
+                    return Ok(res);
+                }
+            }
+            n += 1;
+        }
+        anyhow::bail!("tracker did not answer")
+    }
+
+    /// Asks for a new connection id, as the nth transmission. None if the
+    /// tracker was too slow.
+    async fn connect(&mut self, n: u32) -> anyhow::Result<Option<u64>> {
+        let Some(res) = self
+            .exchange(UDP_PROTOCOL_ID, ACTION_CONNECT, &[], n)
+            .await?
+        else {
+            return Ok(None);
+        };
+        if res.len() < 8 {
+            anyhow::bail!("connect response too short");
+        }
+        let id = u64::from_be_bytes(res[..8].try_into()?);
+        self.connection = Some((id, Instant::now()));
+        Ok(Some(id))
+    }
+
+    /// Sends one packet and waits 15 * 2^n seconds (with the default timeout)
+    /// for the response with the same transaction id. Returns what follows the
+    /// action and transaction id, or None if the tracker was too slow.
+    async fn exchange(
+        &mut self,
+        connection_id: u64,
+        action: u32,
+        body: &[u8],
+        n: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        self.socket.send(&packet).await?;
+
+        let deadline = tokio::time::Instant::now() + self.base_timeout * 2u32.pow(n);
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(len) => len?,
+                Err(_) => return Ok(None),
+            };
+            // anything that isn't an answer to this packet is stale
+            if len < 8 || read_u32(&buf, 4) != transaction_id {
+                continue;
+            }
+            return match read_u32(&buf, 0) {
+                ACTION_ERROR => {
+                    anyhow::bail!("tracker error: {}", String::from_utf8_lossy(&buf[8..len]))
+                }
+                a if a == action => Ok(Some(buf[8..len].to_vec())),
+                a => anyhow::bail!("tracker answered with action {}", a),
+            };
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}